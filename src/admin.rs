@@ -0,0 +1,423 @@
+/*
+ * Administrative commands, sent by private message
+ *
+ */
+use failure::{Error, bail};
+use toml::value::{Table, Value};
+use log::info;
+use chrono::Utc;
+
+use crate::{
+    config::{Rtd, Conf, is_channel},
+    history::HistoryStore,
+    hostmask::Source,
+    ignore::{self, Kind},
+    message::autosave,
+    queue::SendQueue,
+};
+
+/// Admin commands, and their usage
+pub const ADMIN_COMMANDS: [(&str, &str); 10] = [
+    ("help", ""),
+    ("join", "<channel>"),
+    ("part", "<channel>"),
+    ("ignore", "[nick|mask|regex|account] <value>"),
+    ("unignore", "[nick|mask|regex|account] <value>"),
+    ("ignores", ""),
+    ("set", "<feature|parameter|http> <name> <value>"),
+    ("reload", ""),
+    ("save", ""),
+    ("failures", "[count]"),
+];
+
+/// Check whether the sender of a message is permitted to run admin commands,
+/// either by matching hostmask, or by services account.
+pub fn is_admin(conf: &Conf, source: &Source) -> bool {
+    source.matches_any(&conf.admin.hostmasks)
+        || source.account_in(&conf.admin.accounts)
+}
+
+/// Run the admin command given in a message, if any
+///
+/// Returns `None` if the message is not an admin command.
+pub fn dispatch(
+    queue: &SendQueue,
+    rtd: &mut Rtd,
+    db: &dyn HistoryStore,
+    text: &str,
+) -> Option<Result<String, Error>> {
+    let text = text.trim();
+    let (name, rest) = match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim()),
+        None => (text, ""),
+    };
+    let name = name.to_lowercase();
+
+    let (name, usage) = ADMIN_COMMANDS.iter().find(|(n, _)| *n == name)?;
+    let args: Vec<&str> = rest.split_whitespace().collect();
+
+    info!("[{}] ADMIN {} {:?}", rtd.conf.network.name, name, rest);
+
+    let result = match (*name, &args[..]) {
+        ("help", []) => Ok(help()),
+        ("join", [chan]) => join(queue, rtd, chan),
+        ("part", [chan]) => part(queue, rtd, chan),
+        ("ignore", [_, ..]) => ignore(rtd, rest, true),
+        ("unignore", [_, ..]) => ignore(rtd, rest, false),
+        ("ignores", []) => Ok(ignores(rtd)),
+        ("set", [section, field, _, ..]) => {
+            set(rtd, section, field, skip_words(rest, 2))
+        },
+        ("reload", []) => reload(queue, rtd),
+        ("save", []) => rtd.conf.save().map(|_| "configuration saved".into()),
+        ("failures", []) => failures(rtd, db, "5"),
+        ("failures", [count]) => failures(rtd, db, count),
+        _ => Err(failure::format_err!("usage: {} {}", name, usage)),
+    };
+
+    Some(result)
+}
+
+/// Get the text following the first `n` whitespace-separated words
+fn skip_words(text: &str, n: usize) -> &str {
+    (0..n).fold(text.trim(), |rest, _| match rest.find(char::is_whitespace) {
+        Some(i) => rest[i..].trim_start(),
+        None => "",
+    })
+}
+
+fn help() -> String {
+    let commands: Vec<_> = ADMIN_COMMANDS
+        .iter()
+        .map(|(n, u)| if u.is_empty() { n.to_string() } else { format!("{} {}", n, u) })
+        .collect();
+
+    format!("admin commands: {}", commands.join(", "))
+}
+
+fn join(queue: &SendQueue, rtd: &mut Rtd, chan: &str) -> Result<String, Error> {
+    if !is_channel(chan) {
+        bail!("not a channel: {}", chan);
+    }
+
+    queue.send_join(chan);
+    rtd.conf.add_channel(chan.to_string());
+    autosave(rtd);
+
+    Ok(format!("joined {}", chan))
+}
+
+fn part(queue: &SendQueue, rtd: &mut Rtd, chan: &str) -> Result<String, Error> {
+    if !is_channel(chan) {
+        bail!("not a channel: {}", chan);
+    }
+
+    queue.send_part(chan);
+    rtd.conf.remove_channel(chan);
+    autosave(rtd);
+
+    Ok(format!("parted {}", chan))
+}
+
+/// Add or remove an ignore, given as an optional kind, followed by a value
+fn ignore(rtd: &mut Rtd, args: &str, add: bool) -> Result<String, Error> {
+    let (kind, value) = match args.split_once(char::is_whitespace) {
+        Some((kind, value)) => (kind.parse()?, value.trim()),
+        None => (Kind::guess(args), args),
+    };
+
+    let params = &mut rtd.conf.params;
+
+    if add {
+        ignore::add(params, kind, value)?;
+    } else {
+        ignore::remove(params, kind, value)?;
+    }
+
+    rtd.update_channels()?;
+
+    autosave(rtd);
+
+    if add {
+        Ok(format!("ignoring {}", value))
+    } else {
+        Ok(format!("no longer ignoring {}", value))
+    }
+}
+
+fn ignores(rtd: &Rtd) -> String {
+    match ignore::describe(&rtd.conf.params) {
+        ref d if d.is_empty() => "not ignoring anyone".into(),
+        d => d,
+    }
+}
+
+/// List the hosts which have failed most often within the failure window
+fn failures(rtd: &Rtd, db: &dyn HistoryStore, count: &str) -> Result<String, Error> {
+    let count: u32 = count.parse()?;
+    let window = rtd.conf.http_params.failure_window_s;
+    let since = Utc::now() - chrono::Duration::seconds(window as i64);
+
+    let hosts: Vec<_> = db.failing_hosts(since, count)?
+        .iter()
+        .map(|h| match &h.last_kind {
+            Some(kind) => format!("{} {} (last: {})", h.host, h.count, kind),
+            None => format!("{} {}", h.host, h.count),
+        })
+        .collect();
+
+    if hosts.is_empty() {
+        Ok(format!("no failures in the last {}s", window))
+    } else {
+        Ok(format!("failing hosts: {}", hosts.join(", ")))
+    }
+}
+
+/// Parse a value given to `set` as TOML, or otherwise as a plain string
+fn parse_value(value: &str) -> Value {
+    match value {
+        "on" | "yes" => Value::Boolean(true),
+        "off" | "no" => Value::Boolean(false),
+        _ => toml::de::from_str::<Table>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut t| t.remove("value"))
+            .unwrap_or_else(|| Value::String(value.to_string())),
+    }
+}
+
+fn set(rtd: &mut Rtd, section: &str, field: &str, value: &str) -> Result<String, Error> {
+    let value = parse_value(value);
+
+    rtd.conf.set(section, field, value.clone())?;
+
+    // HTTP settings are applied on creating the client
+    if section == "http" {
+        *rtd = rtd.clone().init_http_client()?;
+    } else {
+        rtd.update_channels()?;
+    }
+
+    autosave(rtd);
+
+    Ok(format!("set {} {} = {}", section, field, value))
+}
+
+fn reload(queue: &SendQueue, rtd: &mut Rtd) -> Result<String, Error> {
+    let mut conf = rtd.conf.reload()?;
+
+    // the version is set at run time
+    conf.client.version = rtd.conf.client.version.clone();
+
+    let old = rtd.conf.client.channels.clone().unwrap_or_default();
+    let new = conf.client.channels.clone().unwrap_or_default();
+
+    *rtd = rtd.clone().conf(conf).init_http_client()?;
+
+    for chan in new.iter().filter(|c| !old.contains(c)) {
+        queue.send_join(chan);
+    }
+
+    for chan in old.iter().filter(|c| !new.contains(c)) {
+        queue.send_part(chan);
+    }
+
+    Ok("configuration reloaded".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{feat, param, config::Parameters};
+    use irc::client::prelude::*;
+    use irc::client::data::Config as IrcConfig;
+    use tempfile::tempdir;
+    use crate::sqlite::Database;
+
+    fn queue() -> SendQueue {
+        let client = IrcClient::from_config(IrcConfig {
+            nickname: Some("url-bot-rs".into()),
+            use_mock_connection: Some(true),
+            ..IrcConfig::default()
+        }).unwrap();
+        SendQueue::new(client, &Parameters::default())
+    }
+
+    fn run(queue: &SendQueue, rtd: &mut Rtd, text: &str) -> Result<String, Error> {
+        let db = Database::open_in_memory().unwrap();
+        dispatch(queue, rtd, &db, text).unwrap()
+    }
+
+    #[test]
+    fn test_is_admin() {
+        let mut conf = Conf::default();
+        let msg: Message = ":nick!user@host.example.com PRIVMSG url-bot-rs :help"
+            .parse().unwrap();
+        let tagged: Message = "@account=Bob :nick!user@host PRIVMSG url-bot-rs :help"
+            .parse().unwrap();
+
+        let untagged: Message = ":bob!user@host PRIVMSG url-bot-rs :help"
+            .parse().unwrap();
+        let is_admin = |conf: &Conf, m: &Message| is_admin(conf, &Source::new(m).unwrap());
+
+        assert!(!is_admin(&conf, &msg));
+        assert!(!is_admin(&conf, &tagged));
+
+        conf.admin.hostmasks = vec!["nick!user@*.org".into()];
+        assert!(!is_admin(&conf, &msg));
+        conf.admin.hostmasks.push("*!*@*.example.com".into());
+        assert!(is_admin(&conf, &msg));
+        assert!(!is_admin(&conf, &tagged));
+
+        conf.admin.accounts = vec!["bob".into()];
+        assert!(is_admin(&conf, &tagged));
+        assert!(!is_admin(&conf, &untagged));
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let queue = queue();
+        let mut rtd = Rtd::default();
+        let db = Database::open_in_memory().unwrap();
+
+        assert!(dispatch(&queue, &mut rtd, &db, "").is_none());
+        assert!(dispatch(&queue, &mut rtd, &db, "hello").is_none());
+        assert!(dispatch(&queue, &mut rtd, &db, "http://example.com").is_none());
+        assert!(run(&queue, &mut rtd, "HELP").unwrap().starts_with("admin commands: help, join"));
+        assert_eq!("usage: join <channel>", run(&queue, &mut rtd, "join").unwrap_err().to_string());
+        assert!(run(&queue, &mut rtd, "join #a #b").is_err());
+        assert!(run(&queue, &mut rtd, "join nick").is_err());
+    }
+
+    #[test]
+    fn test_join_part() {
+        let queue = queue();
+        let mut rtd = Rtd::default();
+
+        run(&queue, &mut rtd, "join #test").unwrap();
+        assert_eq!(Some(vec!["#url-bot-rs".into(), "#test".into()]), rtd.conf.client.channels);
+
+        run(&queue, &mut rtd, "part #url-bot-rs").unwrap();
+        assert_eq!(Some(vec!["#test".into()]), rtd.conf.client.channels);
+    }
+
+    #[test]
+    fn test_ignore() {
+        let queue = queue();
+        let mut rtd = Rtd::default();
+
+        run(&queue, &mut rtd, "ignore otherbot").unwrap();
+        assert_eq!(vec!["otherbot"], param!(rtd, ignore_nicks));
+        assert!(run(&queue, &mut rtd, "ignore otherbot").is_err());
+
+        run(&queue, &mut rtd, "unignore otherbot").unwrap();
+        assert!(param!(rtd, ignore_nicks).is_empty());
+        assert!(run(&queue, &mut rtd, "unignore otherbot").is_err());
+
+        assert_eq!("not ignoring anyone", run(&queue, &mut rtd, "ignores").unwrap());
+
+        run(&queue, &mut rtd, "ignore *!*@bots.example.com").unwrap();
+        run(&queue, &mut rtd, "ignore regex ^bot\\d+ ?$").unwrap();
+        run(&queue, &mut rtd, "ignore account robot").unwrap();
+        assert_eq!(vec!["*!*@bots.example.com"], param!(rtd, ignore_masks));
+        assert_eq!("^bot\\d+ ?$", param!(rtd, ignore_nick_regexes)[0].0.as_str());
+        assert_eq!(vec!["robot"], param!(rtd, ignore_accounts));
+        assert!(run(&queue, &mut rtd, "ignore regex (").is_err());
+        assert!(run(&queue, &mut rtd, "ignore user foo").is_err());
+
+        run(&queue, &mut rtd, "unignore regex ^bot\\d+ ?$").unwrap();
+        assert!(param!(rtd, ignore_nick_regexes).is_empty());
+    }
+
+    #[test]
+    fn test_set() {
+        let queue = queue();
+        let mut rtd = Rtd::default();
+
+        run(&queue, &mut rtd, "set feature history on").unwrap();
+        assert!(feat!(rtd, history));
+        run(&queue, &mut rtd, "set features history false").unwrap();
+        assert!(!feat!(rtd, history));
+
+        run(&queue, &mut rtd, "set parameter url_limit 3").unwrap();
+        assert_eq!(3, param!(rtd, url_limit));
+        run(&queue, &mut rtd, "set parameter nick_response_str hello there").unwrap();
+        assert_eq!("hello there", param!(rtd, nick_response_str));
+        run(&queue, &mut rtd, "set  parameter \t nick_response_str   hi  there ").unwrap();
+        assert_eq!("hi  there", param!(rtd, nick_response_str));
+        run(&queue, &mut rtd, r##"set parameter status_channels ["#a", "#b"]"##).unwrap();
+        assert_eq!(vec!["#a", "#b"], param!(rtd, status_channels));
+
+        run(&queue, &mut rtd, "set http timeout_s 1").unwrap();
+        assert!(rtd.get_client().is_ok());
+
+        assert!(run(&queue, &mut rtd, "set feature history").is_err());
+        assert!(run(&queue, &mut rtd, "set feature nonexistent on").is_err());
+        assert!(run(&queue, &mut rtd, "set feature history 3").is_err());
+        assert!(run(&queue, &mut rtd, "set nonexistent history on").is_err());
+    }
+
+    #[test]
+    fn test_failures() {
+        let queue = queue();
+        let mut rtd = Rtd::default();
+        let db = Database::open_in_memory().unwrap();
+        let mut run = |text| dispatch(&queue, &mut rtd, &db, text).unwrap();
+
+        assert_eq!("no failures in the last 900s", run("failures").unwrap());
+        assert!(run("failures many").is_err());
+
+        for host in ["a.com", "b.com", "a.com"].iter() {
+            db.add_error(&crate::history::NewErrorEntry {
+                url: "http://a.com/",
+                error_info: "error",
+                host,
+                kind: "timeout",
+                status: None,
+                host_failure: true,
+                network: "net",
+                channel: "#c",
+            }).unwrap();
+        }
+
+        assert_eq!("failing hosts: a.com 2 (last: timeout), b.com 1 (last: timeout)",
+            run("failures").unwrap());
+        assert_eq!("failing hosts: a.com 2 (last: timeout)", run("failures 1").unwrap());
+    }
+
+    #[test]
+    fn test_save_reload() {
+        let queue = queue();
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("config.toml");
+        Conf::default().write(&path).unwrap();
+
+        let mut rtd = Rtd::default().conf(Conf::load(&path).unwrap());
+
+        run(&queue, &mut rtd, "join #test").unwrap();
+        run(&queue, &mut rtd, "set feature history on").unwrap();
+        run(&queue, &mut rtd, "reload").unwrap();
+        assert!(!feat!(rtd, history));
+
+        run(&queue, &mut rtd, "join #test").unwrap();
+        run(&queue, &mut rtd, "set feature history on").unwrap();
+        run(&queue, &mut rtd, "save").unwrap();
+
+        let conf = Conf::load(&path).unwrap();
+        assert!(conf.features.history);
+        assert_eq!(Some(vec!["#url-bot-rs".into(), "#test".into()]), conf.client.channels);
+    }
+
+    #[test]
+    fn test_autosave() {
+        let queue = queue();
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("config.toml");
+        Conf::default().write(&path).unwrap();
+
+        let mut rtd = Rtd::default().conf(Conf::load(&path).unwrap());
+        feat!(rtd, autosave) = true;
+
+        run(&queue, &mut rtd, "ignore otherbot").unwrap();
+        assert_eq!(vec!["otherbot"], Conf::load(&path).unwrap().params.ignore_nicks);
+    }
+}
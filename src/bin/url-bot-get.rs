@@ -63,7 +63,7 @@ use docopt::Docopt;
 use stderrlog::{Timestamp, ColorChoice};
 use atty::{is, Stream};
 use serde_derive::Deserialize;
-use log::error;
+use log::{info, error};
 use failure::{Error, bail};
 use reqwest::Url;
 
@@ -71,7 +71,8 @@ use url_bot_rs::{
     VERSION, feat,
     config::{Rtd, Http},
     http::{RetrieverBuilder, get_title},
-    message::add_scheme_for_tld,
+    extract::add_scheme_for_tld,
+    title::url_similarity,
     plugins::{TITLE_PLUGINS, PluginConfig},
 };
 
@@ -206,6 +207,9 @@ fn scrape_title(args: &Args) -> Result<(), Error> {
     let ret = match get_title(&mut resp, &rtd, args.flag_curl) {
         Ok(t) => {
             if !args.flag_curl { println!("{}", t) };
+            if let Ok(url) = Url::parse(&token) {
+                info!("similarity to URL: {:.2}", url_similarity(&url, &t));
+            }
             0
         },
         Err(e) => {
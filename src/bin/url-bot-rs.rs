@@ -5,21 +5,33 @@
  *
  */
 
+// older tests predate these lints
+#![cfg_attr(test, allow(
+    clippy::field_reassign_with_default,
+    clippy::needless_borrows_for_generic_args,
+))]
+
 use url_bot_rs::VERSION;
-use url_bot_rs::sqlite::Database;
+use url_bot_rs::history::Stores;
 use url_bot_rs::config::{
     Rtd,
     Conf,
+    DbType,
     find_configs_in_dir,
     ensure_parent_dir,
     load_flattened_configs,
 };
 use url_bot_rs::message::handle_message;
+use url_bot_rs::queue::SendQueue;
+use url_bot_rs::worker::Workers;
+use url_bot_rs::cache::TitleCache;
+use url_bot_rs::ircv3;
 use url_bot_rs::{feat, param};
 
 use docopt::Docopt;
 use failure::Error;
 use irc::client::prelude::*;
+use irc::error::IrcError;
 use std::process;
 use std::thread;
 use std::time::Duration;
@@ -75,6 +87,9 @@ fn main() {
             "url_bot_rs::message",
             "url_bot_rs::config",
             "url_bot_rs::http",
+            "url_bot_rs::commands",
+            "url_bot_rs::admin",
+            "url_bot_rs::queue",
         ])
         .verbosity(args.flag_verbose + MIN_VERBOSITY)
         .timestamp(timestamp)
@@ -101,12 +116,21 @@ fn run(args: Args) -> Result<(), Error> {
     // create a list of configurations
     let configs: Vec<Conf> = load_flattened_configs(config_paths);
 
+    // title lookups are cached for all networks
+    let cache_size = configs.iter().map(|c| c.http_params.cache_size).max();
+    let cache = TitleCache::new(cache_size.unwrap_or(0) as usize);
+
+    // networks with shared history use one store
+    let stores = Stores::default();
+
     // threaded instances
     let threads: Vec<_> = configs
         .into_iter()
         .map(|conf| {
+            let cache = cache.clone();
+            let stores = stores.clone();
             thread::spawn(move || {
-                run_instance(conf).unwrap_or_else(|e| {
+                run_instance(conf, cache, stores).unwrap_or_else(|e| {
                     error!("{}", e);
                     process::exit(1);
                 });
@@ -181,7 +205,7 @@ fn create_default_configs(paths: &[PathBuf]) -> Result<(), Error> {
 }
 
 /// Run an instance, handling restart if configured.
-fn run_instance(conf: Conf) -> Result<(), Error> {
+fn run_instance(conf: Conf, cache: TitleCache, stores: Stores) -> Result<(), Error> {
     let net = conf.network.name.clone();
 
     if let Some(ref path) = conf.path {
@@ -195,6 +219,8 @@ fn run_instance(conf: Conf) -> Result<(), Error> {
 
     let rtd: Rtd = Rtd::new()
         .conf(conf)
+        .cache(cache)
+        .stores(stores)
         .load()?
         .init_http_client()?;
 
@@ -217,31 +243,40 @@ fn run_instance(conf: Conf) -> Result<(), Error> {
 }
 
 /// Connect to a server and handle IRC messages.
+#[allow(clippy::result_large_err)]
 fn connect_instance(rtd: &Rtd) -> Result<(), Error> {
     let mut rtd = rtd.clone();
     let net = &rtd.conf.network.name;
 
-    let db = if let Some(ref path) = rtd.paths.db {
-        info!("[{}] using database: {}", net, path.display());
-        Database::open(path)?
-    } else {
-        Database::open_in_memory()?
-    };
+    let db = rtd.stores.open(&rtd)?;
 
-    if feat!(rtd, history) && rtd.paths.db.is_none() {
-        info!("[{}] using in-memory database", net);
+    if feat!(rtd, history) {
+        match rtd.paths.db {
+            Some(ref path) => info!("[{}] using database: {}", net, path.display()),
+            None if rtd.conf.database.db_type == DbType::InMemory => {
+                info!("[{}] using in-memory database", net)
+            },
+            None => info!("[{}] using postgres database", net),
+        }
     }
 
     let mut reactor = IrcReactor::new()?;
 
     let client = reactor.prepare_client_and_connect(&rtd.conf.client)?;
-    client.identify()?;
+
+    ircv3::register(&client)?;
 
     info!("[{}] connected", net);
 
-    reactor.register_client_with_handler(client, move |client, message| {
-        handle_message(client, &message, &mut rtd, &db);
-        Ok(())
+    let queue = SendQueue::new(client.clone(), &rtd.conf.params);
+    let workers = Workers::new(
+        param!(rtd, workers).into(),
+        param!(rtd, worker_queue) as usize,
+    );
+
+    reactor.register_client_with_handler(client, move |_, message| {
+        handle_message(&queue, &workers, &message, &mut rtd, &db)
+            .map_err(|inner| IrcError::Custom { inner })
     });
 
     reactor.run()?;
@@ -255,8 +290,106 @@ mod tests {
     use std::fs;
     use std::env;
     use std::path::Path;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
     use tempfile::tempdir;
     use url_bot_rs::config::Conf;
+    use url_bot_rs::sasl::{Sasl, Mechanism};
+
+    /// A scripted stand-in for an IRC server, which waits for lines starting
+    /// with each expected prefix in turn, and answers with the given lines,
+    /// closing the connection at the end of the script
+    fn irc_server(script: Vec<(&'static str, Vec<&'static str>)>)
+        -> (u16, thread::JoinHandle<Vec<String>>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = vec![];
+
+            for (expect, replies) in script {
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 {
+                        return received;
+                    }
+                    received.push(line.trim_end().to_string());
+                    if line.starts_with(expect) {
+                        break;
+                    }
+                }
+                for reply in replies {
+                    write!(stream, "{}\r\n", reply).unwrap();
+                }
+            }
+
+            received
+        });
+
+        (port, server)
+    }
+
+    fn sasl_rtd(port: u16) -> Rtd {
+        let mut conf = Conf::default();
+        conf.client.port = Some(port);
+        conf.client.channels = None;
+        conf.client.nickname = Some("bot".into());
+        conf.sasl = Sasl {
+            mechanism: Mechanism::Plain,
+            username: "user".into(),
+            password: "pass".into(),
+        };
+        Rtd::new().conf(conf)
+    }
+
+    #[test]
+    fn test_sasl() {
+        let (port, server) = irc_server(vec![
+            ("USER", vec![":irc.test CAP * LS :batch sasl=PLAIN,EXTERNAL"]),
+            ("CAP REQ", vec![":irc.test CAP bot ACK :batch sasl"]),
+            ("AUTHENTICATE PLAIN", vec!["AUTHENTICATE +"]),
+            ("AUTHENTICATE AHVzZXIAcGFzcw==", vec![
+                ":irc.test 900 bot bot!bot@host user :You are now logged in as user",
+                ":irc.test 903 bot :SASL authentication successful",
+            ]),
+            ("CAP END", vec![":irc.test 001 bot :Welcome bot!bot@host"]),
+        ]);
+
+        assert!(connect_instance(&sasl_rtd(port)).is_ok());
+        let received = server.join().unwrap();
+        assert_eq!(received[0], "CAP LS 302");
+        assert_eq!(received.last().unwrap(), "CAP END");
+    }
+
+    #[test]
+    fn test_sasl_failure() {
+        let (port, server) = irc_server(vec![
+            ("USER", vec![":irc.test CAP * LS :sasl"]),
+            ("CAP REQ :sasl", vec![":irc.test CAP bot ACK :sasl"]),
+            ("AUTHENTICATE PLAIN", vec!["AUTHENTICATE +"]),
+            ("AUTHENTICATE ", vec![":irc.test 904 bot :SASL authentication failed"]),
+            ("CAP END", vec![]),
+        ]);
+
+        let err = connect_instance(&sasl_rtd(port)).unwrap_err();
+        assert_eq!("SASL authentication failed: SASL authentication failed", err.to_string());
+        assert!(!server.join().unwrap().iter().any(|l| l == "CAP END"));
+    }
+
+    #[test]
+    fn test_sasl_unsupported() {
+        let (port, server) = irc_server(vec![
+            ("USER", vec![":irc.test CAP * LS :batch"]),
+            ("CAP END", vec![]),
+        ]);
+
+        let err = connect_instance(&sasl_rtd(port)).unwrap_err();
+        assert_eq!("the server doesn't support SASL authentication", err.to_string());
+        assert!(!server.join().unwrap().iter().any(|l| l == "CAP END"));
+    }
 
     #[test]
     fn test_get_cli_configs() {
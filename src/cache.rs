@@ -0,0 +1,257 @@
+/*
+ * Cache of title lookups
+ *
+ */
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use reqwest::Url;
+
+use crate::http::{PageInfo, Failure};
+
+/// The result of looking up a title
+pub type Lookup = Result<PageInfo, Failure>;
+
+#[derive(Debug)]
+struct Entry {
+    lookup: Lookup,
+    time: Instant,
+    /// when the entry was last used, as a key of `Inner::order`
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    capacity: usize,
+    entries: HashMap<String, Entry>,
+    /// keys, least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Inner {
+    fn touch(&mut self, key: &str) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.used);
+            self.tick += 1;
+            entry.used = self.tick;
+            self.order.insert(self.tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+        }
+    }
+
+    fn get(&mut self, key: &str, ttl: Duration, error_ttl: Duration, now: Instant) -> Option<Lookup> {
+        let fresh = self.entries.get(key).map(|e| {
+            let ttl = if e.lookup.is_ok() { ttl } else { error_ttl };
+            now.saturating_duration_since(e.time) < ttl
+        });
+
+        match fresh {
+            Some(true) => {
+                self.hits += 1;
+                self.touch(key);
+                self.entries.get(key).map(|e| e.lookup.clone())
+            },
+            Some(false) => {
+                self.misses += 1;
+                self.remove(key);
+                None
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    fn insert(&mut self, key: String, lookup: Lookup, now: Instant) {
+        self.remove(&key);
+
+        while self.entries.len() >= self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => { self.entries.remove(&oldest); },
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, Entry { lookup, time: now, used: self.tick });
+    }
+}
+
+/// Cache counters
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} entries, {} hits, {} misses", self.entries, self.hits, self.misses)
+    }
+}
+
+/// A least recently used cache of title lookups, by URL, shared between
+/// clones
+///
+/// Entries expire after a time to live given when they're looked up, which
+/// may be different for successful lookups and errors. A cache with no
+/// capacity is disabled.
+#[derive(Debug, Default, Clone)]
+pub struct TitleCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl TitleCache {
+    pub fn new(capacity: usize) -> Self {
+        let inner = Inner { capacity, ..Inner::default() };
+
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.inner.lock().unwrap().capacity > 0
+    }
+
+    /// Get the key a URL is cached by, ignoring any fragment, along with a
+    /// fingerprint of the settings it's looked up with, see
+    /// `Conf::lookup_fingerprint`
+    pub fn key(url: &Url, fingerprint: u64) -> String {
+        let mut url = url.clone();
+        url.set_fragment(None);
+        format!("{:016x} {}", fingerprint, url)
+    }
+
+    /// Get a lookup, if it was cached within its time to live
+    pub fn get(&self, key: &str, ttl: Duration, error_ttl: Duration) -> Option<Lookup> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.capacity == 0 {
+            return None;
+        }
+
+        inner.get(key, ttl, error_ttl, Instant::now())
+    }
+
+    /// Cache a lookup, evicting the least recently used if the cache is full
+    pub fn insert(&self, key: String, lookup: Lookup) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.capacity == 0 {
+            return;
+        }
+
+        inner.insert(key, lookup, Instant::now());
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+
+        CacheStats {
+            entries: inner.entries.len(),
+            hits: inner.hits,
+            misses: inner.misses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::FailureKind;
+
+    fn page(title: &str) -> Lookup {
+        Ok(PageInfo { title: title.into(), ..PageInfo::default() })
+    }
+
+    fn title(lookup: Option<Lookup>) -> Option<String> {
+        lookup.map(|l| l.map_or_else(|e| e.message, |p| p.title))
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let now = Instant::now();
+        let (ttl, error_ttl) = (Duration::from_secs(60), Duration::from_secs(10));
+        let mut c = Inner { capacity: 10, ..Inner::default() };
+        let get = |c: &mut Inner, key, secs| {
+            title(c.get(key, ttl, error_ttl, now + Duration::from_secs(secs)))
+        };
+
+        assert_eq!(None, get(&mut c, "a", 0));
+        c.insert("a".into(), page("A"), now);
+        c.insert("e".into(), Err(Failure::new(FailureKind::Other, "error")), now);
+
+        assert_eq!(Some("A".into()), get(&mut c, "a", 30));
+        assert_eq!(Some("error".into()), get(&mut c, "e", 5));
+        assert_eq!(None, get(&mut c, "e", 10));
+        assert_eq!(None, get(&mut c, "a", 60));
+        assert!(c.entries.is_empty());
+        assert!(c.order.is_empty());
+        assert_eq!((2, 3), (c.hits, c.misses));
+    }
+
+    #[test]
+    fn test_cache_lru() {
+        let now = Instant::now();
+        let ttl = Duration::from_secs(60);
+        let mut c = Inner { capacity: 2, ..Inner::default() };
+
+        c.insert("a".into(), page("A"), now);
+        c.insert("b".into(), page("B"), now);
+        assert!(c.get("a", ttl, ttl, now).is_some());
+
+        // "b" is the least recently used
+        c.insert("c".into(), page("C"), now);
+        assert!(c.get("b", ttl, ttl, now).is_none());
+        assert!(c.get("a", ttl, ttl, now).is_some());
+        assert!(c.get("c", ttl, ttl, now).is_some());
+
+        // replacing an entry doesn't evict another
+        c.insert("c".into(), page("C2"), now);
+        assert_eq!(Some("C2".into()), title(c.get("c", ttl, ttl, now)));
+        assert_eq!(2, c.entries.len());
+        assert_eq!(2, c.order.len());
+    }
+
+    #[test]
+    fn test_cache_shared() {
+        let ttl = Duration::from_secs(60);
+        let cache = TitleCache::new(10);
+        let other = cache.clone();
+
+        cache.insert("a".into(), page("A"));
+        assert_eq!(Some("A".into()), title(other.get("a", ttl, ttl)));
+        assert_eq!(None, title(other.get("b", ttl, ttl)));
+        assert_eq!(CacheStats { entries: 1, hits: 1, misses: 1 }, cache.stats());
+        assert_eq!("1 entries, 1 hits, 1 misses", cache.stats().to_string());
+
+        // a cache with no capacity is disabled
+        let cache = TitleCache::new(0);
+        cache.insert("a".into(), page("A"));
+        assert_eq!(None, title(cache.get("a", ttl, ttl)));
+        assert_eq!(CacheStats::default(), cache.stats());
+        assert!(!cache.enabled());
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let key = |u| TitleCache::key(&Url::parse(u).unwrap(), 0x1f);
+        assert_eq!("000000000000001f https://example.com/a?b", key("HTTPS://Example.COM/a?b#c"));
+        assert_eq!("000000000000001f http://example.com/", key("http://example.com"));
+    }
+}
@@ -0,0 +1,238 @@
+/*
+ * URL canonicalisation, for history and de-duplication
+ *
+ */
+use reqwest::Url;
+use serde_derive::{Serialize, Deserialize};
+
+/// Rules for reducing URLs to a key, which is the same for links to the same
+/// page, e.g. ignoring the scheme, `www.`, and tracking parameters
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Canonical {
+    /// apply the built-in rules for common sites, e.g. `youtu.be` links are
+    /// the same as `youtube.com/watch?v=` links
+    pub builtin: bool,
+    /// use the `<link rel="canonical">` of fetched pages, if it's on the
+    /// same site
+    pub link_canonical: bool,
+    /// query parameters to ignore, matching any name with the prefix if they
+    /// end with `*`
+    pub tracking_params: Vec<String>,
+}
+
+impl Default for Canonical {
+    fn default() -> Self {
+        Self {
+            builtin: true,
+            link_canonical: true,
+            tracking_params: [
+                "utm_*", "fbclid", "gclid", "dclid", "msclkid", "mc_cid",
+                "mc_eid", "igshid", "yclid", "_ga", "ref_src",
+            ].iter().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+/// the parts of a URL which make up its key
+struct Parts {
+    host: String,
+    path: String,
+    query: Vec<String>,
+}
+
+fn param_name(param: &str) -> &str {
+    param.split('=').next().unwrap_or_default()
+}
+
+/// rewrite links to common sites which have several forms
+fn builtin(parts: &mut Parts) {
+    let segments: Vec<&str> = parts.path.split('/').filter(|s| !s.is_empty()).collect();
+
+    let video = match (parts.host.as_str(), segments.as_slice()) {
+        ("youtu.be", [id, ..]) => Some(id.to_string()),
+        ("youtube.com" | "m.youtube.com", ["shorts" | "embed" | "live", id, ..]) => {
+            Some(id.to_string())
+        },
+        ("youtube.com" | "m.youtube.com", ["watch"]) => parts.query
+            .iter()
+            .find(|p| param_name(p) == "v")
+            .and_then(|p| p.split_once('='))
+            .map(|(_, id)| id.to_string())
+            .filter(|id| !id.is_empty()),
+        _ => None,
+    };
+
+    if let Some(id) = video {
+        parts.host = "youtube.com".into();
+        parts.path = "/watch".into();
+        parts.query = vec![format!("v={}", id)];
+        return;
+    }
+
+    let host = match parts.host.as_str() {
+        "mobile.twitter.com" | "x.com" | "mobile.x.com" => "twitter.com".to_string(),
+        "old.reddit.com" | "np.reddit.com" | "new.reddit.com" => "reddit.com".to_string(),
+        h => match h.strip_suffix(".wikipedia.org").and_then(|l| l.strip_suffix(".m")) {
+            Some(lang) => format!("{}.wikipedia.org", lang),
+            None => return,
+        },
+    };
+
+    parts.host = host;
+}
+
+impl Canonical {
+    fn is_tracking(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+
+        self.tracking_params.iter().any(|p| match p.strip_suffix('*') {
+            Some(prefix) => name.starts_with(&prefix.to_lowercase()),
+            None => name == p.to_lowercase(),
+        })
+    }
+
+    /// Get the key of a URL, which is its host, without any `www.`, port,
+    /// path, and query, without tracking parameters, trailing slashes, or the
+    /// fragment
+    pub fn key(&self, url: &Url) -> String {
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+
+        let mut parts = Parts {
+            host: host.strip_prefix("www.").unwrap_or(&host).to_string(),
+            path: url.path().trim_end_matches('/').to_string(),
+            query: url.query()
+                .unwrap_or_default()
+                .split('&')
+                .filter(|p| !p.is_empty() && !self.is_tracking(param_name(p)))
+                .map(str::to_string)
+                .collect(),
+        };
+
+        if self.builtin {
+            builtin(&mut parts);
+        }
+
+        let mut key = parts.host;
+
+        if let Some(port) = url.port() {
+            key.push_str(&format!(":{}", port));
+        }
+
+        key.push_str(&parts.path);
+
+        if !parts.query.is_empty() {
+            key.push('?');
+            key.push_str(&parts.query.join("&"));
+        }
+
+        key
+    }
+
+    /// Get the key of the canonical link of a fetched page, if it should be
+    /// used instead of that of the URL of the page
+    ///
+    /// Links to other sites are ignored, as are links to the root of the site
+    /// from other pages, which some sites give for every page.
+    pub fn link_key(&self, url: &Url, link: Option<&str>) -> Option<String> {
+        if !self.link_canonical {
+            return None;
+        }
+
+        let link = Url::parse(link?).ok()
+            .filter(|l| ["http", "https"].contains(&l.scheme()))?;
+
+        let host = |u: &Url| {
+            let h = u.host_str().unwrap_or_default().to_lowercase();
+            h.strip_prefix("www.").map(str::to_string).unwrap_or(h)
+        };
+
+        if host(&link) != host(url) {
+            return None;
+        }
+
+        if link.path() == "/" && url.path() != "/" {
+            return None;
+        }
+
+        Some(self.key(&link))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(url: &str) -> String {
+        Canonical::default().key(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_key() {
+        let same = [
+            "http://example.com/a/b",
+            "https://www.example.com/a/b/",
+            "https://EXAMPLE.com/a/b#c",
+            "https://example.com/a/b?utm_source=x&utm_medium=y",
+            "https://example.com/a/b?fbclid=abc",
+        ];
+        for url in same.iter() {
+            assert_eq!("example.com/a/b", key(url));
+        }
+
+        assert_eq!("example.com", key("https://www.example.com/"));
+        assert_eq!("example.com:8080/a", key("http://example.com:8080/a"));
+        assert_eq!("example.com/a?b=1&c=2", key("http://example.com/a?b=1&utm_x=0&c=2"));
+        assert_ne!(key("http://example.com/a?b=1"), key("http://example.com/a?b=2"));
+
+        let canonical = Canonical {
+            tracking_params: vec!["ref".into()],
+            ..Canonical::default()
+        };
+        let url = Url::parse("http://example.com/a?ref=x&utm_source=y").unwrap();
+        assert_eq!("example.com/a?utm_source=y", canonical.key(&url));
+    }
+
+    #[test]
+    fn test_key_builtin() {
+        let video = [
+            "https://youtu.be/abc123?t=10",
+            "https://www.youtube.com/watch?v=abc123&feature=share",
+            "https://m.youtube.com/watch?feature=share&v=abc123",
+            "https://youtube.com/shorts/abc123",
+            "https://www.youtube.com/embed/abc123",
+        ];
+        for url in video.iter() {
+            assert_eq!("youtube.com/watch?v=abc123", key(url));
+        }
+        assert_eq!("youtube.com/channel/x", key("https://www.youtube.com/channel/x"));
+
+        assert_eq!("twitter.com/a/status/1", key("https://x.com/a/status/1"));
+        assert_eq!("twitter.com/a/status/1", key("https://mobile.twitter.com/a/status/1"));
+        assert_eq!("reddit.com/r/rust", key("https://old.reddit.com/r/rust/"));
+        assert_eq!("en.wikipedia.org/wiki/Rust", key("https://en.m.wikipedia.org/wiki/Rust"));
+
+        let canonical = Canonical { builtin: false, ..Canonical::default() };
+        let url = Url::parse("https://youtu.be/abc123").unwrap();
+        assert_eq!("youtu.be/abc123", canonical.key(&url));
+    }
+
+    #[test]
+    fn test_link_key() {
+        let canonical = Canonical::default();
+        let url = Url::parse("https://example.com/a?id=1&page=2").unwrap();
+        let link = |l| canonical.link_key(&url, Some(l));
+
+        assert_eq!(Some("example.com/a?id=1".into()), link("https://www.example.com/a?id=1"));
+        assert_eq!(None, link("https://other.com/a"));
+        assert_eq!(None, link("https://example.com/"));
+        assert_eq!(None, link("not a url"));
+        assert_eq!(None, canonical.link_key(&url, None));
+
+        let root = Url::parse("https://example.com/").unwrap();
+        assert_eq!(Some("example.com".into()), canonical.link_key(&root, Some("https://example.com/")));
+
+        let canonical = Canonical { link_canonical: false, ..Canonical::default() };
+        assert_eq!(None, canonical.link_key(&url, Some("https://example.com/a")));
+    }
+}
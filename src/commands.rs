@@ -0,0 +1,555 @@
+/*
+ * In-channel commands
+ *
+ */
+use failure::{Error, bail};
+use log::{info, error};
+
+use crate::{
+    feat, param,
+    config::{Rtd, Features},
+    history::{HistoryStore, format_time},
+    extract::extract_urls,
+    filter::Action,
+    message::{
+        get_title, render_title, create_non_highlighting_name, is_failing, log_failure,
+        throttle,
+    },
+    ratelimit::Limits,
+};
+
+/// maximum number of posts which may be requested with `last`
+const LAST_MAX: u32 = 10;
+/// number of posts returned by `last` if no count is given
+const LAST_DEFAULT: u32 = 3;
+
+/// The context in which a command is run
+pub struct Context<'a> {
+    pub rtd: &'a Rtd,
+    pub db: &'a dyn HistoryStore,
+    /// nick of the user invoking the command
+    pub sender: &'a str,
+    /// channel, or nick for queries, to which the command was sent
+    pub target: &'a str,
+    pub is_chanmsg: bool,
+}
+
+pub trait BotCommand {
+    /// Get the name of the command, as typed following the prefix
+    fn name(&self) -> &'static str;
+    /// Get a synopsis of the arguments the command takes
+    fn usage(&self) -> &'static str;
+    /// Get a short description of what the command does
+    fn help(&self) -> &'static str;
+    /// Check to see if the command is enabled in the configuration
+    fn enabled(&self, features: &Features) -> bool;
+    /// Run the command, returning lines to send in response
+    fn run(&self, ctx: &Context, args: &[String]) -> Result<Vec<String>, Error>;
+}
+
+/// Command instantiations (as trait objects)
+pub const COMMANDS: [&dyn BotCommand; 3] = [
+    &HelpCommand {},
+    &TitleCommand {},
+    &LastCommand {},
+];
+
+/// Parse a message for a command invocation, returning the (lower case)
+/// command name and its arguments
+pub fn parse(prefix: &str, text: &str) -> Option<(String, Vec<String>)> {
+    if prefix.is_empty() {
+        return None;
+    }
+
+    let text = text.trim().strip_prefix(prefix)?;
+
+    // the command name must directly follow the prefix
+    if text.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let mut words = split_args(text);
+
+    if words.is_empty() {
+        return None;
+    }
+
+    let name = words.remove(0).to_lowercase();
+
+    Some((name, words))
+}
+
+/// Split command arguments, which are separated by whitespace, and may be
+/// grouped using double quotes
+fn split_args(text: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+
+    for c in text.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            },
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(word.split_off(0));
+                    in_word = false;
+                }
+            },
+            c => {
+                word.push(c);
+                in_word = true;
+            },
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+
+    words
+}
+
+/// Find an enabled command by name
+fn find(features: &Features, name: &str) -> Option<&'static dyn BotCommand> {
+    COMMANDS
+        .iter()
+        .find(|c| c.name() == name && c.enabled(features))
+        .copied()
+}
+
+/// Run the command invoked by a message, if any
+///
+/// Returns `None` if the message does not invoke an enabled command.
+pub fn dispatch(ctx: &Context, text: &str) -> Option<Result<Vec<String>, Error>> {
+    let (name, args) = parse(&param!(ctx.rtd, command_prefix), text)?;
+    let command = find(&ctx.rtd.conf.features, &name)?;
+
+    info!("[{}] COMMAND <{}:{}> {} {:?}",
+        ctx.rtd.conf.network.name, ctx.sender, ctx.target, name, args);
+
+    Some(command.run(ctx, &args))
+}
+
+/// Format the usage of a command, including the configured prefix
+fn usage(rtd: &Rtd, command: &dyn BotCommand) -> String {
+    let prefix = &param!(rtd, command_prefix);
+
+    if command.usage().is_empty() {
+        format!("{}{}", prefix, command.name())
+    } else {
+        format!("{}{} {}", prefix, command.name(), command.usage())
+    }
+}
+
+pub struct HelpCommand {}
+
+impl BotCommand for HelpCommand {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[command]"
+    }
+
+    fn help(&self) -> &'static str {
+        "list available commands, or describe a command"
+    }
+
+    fn enabled(&self, features: &Features) -> bool {
+        features.command_help
+    }
+
+    fn run(&self, ctx: &Context, args: &[String]) -> Result<Vec<String>, Error> {
+        let prefix = &param!(ctx.rtd, command_prefix);
+        let features = &ctx.rtd.conf.features;
+
+        let name = match args.first() {
+            Some(name) => name.trim_start_matches(prefix.as_str()).to_lowercase(),
+            None => {
+                let names: Vec<_> = COMMANDS
+                    .iter()
+                    .filter(|c| c.enabled(features))
+                    .map(|c| format!("{}{}", prefix, c.name()))
+                    .collect();
+
+                return Ok(vec![format!("commands: {} (see {})",
+                    names.join(", "), usage(ctx.rtd, self))]);
+            },
+        };
+
+        match find(features, &name) {
+            Some(c) => Ok(vec![format!("{}: {}", usage(ctx.rtd, c), c.help())]),
+            None => bail!("unknown command: {}", name),
+        }
+    }
+}
+
+pub struct TitleCommand {}
+
+impl BotCommand for TitleCommand {
+    fn name(&self) -> &'static str {
+        "title"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<url>"
+    }
+
+    fn help(&self) -> &'static str {
+        "get the title of a URL, ignoring history"
+    }
+
+    fn enabled(&self, features: &Features) -> bool {
+        features.command_title
+    }
+
+    fn run(&self, ctx: &Context, args: &[String]) -> Result<Vec<String>, Error> {
+        let text = args.join(" ");
+
+        let url = extract_urls(&text, feat!(ctx.rtd, partial_urls))
+            .into_iter()
+            .find(|u| ["http", "https"].contains(&u.scheme()));
+
+        let url = match url {
+            Some(url) => url,
+            None => bail!("usage: {}", usage(ctx.rtd, self)),
+        };
+
+        if ctx.rtd.conf.check_url(&url) == Action::Deny {
+            match ctx.rtd.conf.url_filter_message() {
+                "" => bail!("that URL is not permitted"),
+                m => bail!("{}", m),
+            }
+        }
+
+        if is_failing(ctx.rtd, ctx.db, &url) {
+            info!("[{}] FAILING <{}>", ctx.rtd.conf.network.name, url);
+            bail!("that URL has been failing, so I'll skip it for a little while");
+        }
+
+        // lookups are limited as for links posted, letting users know once
+        let limits = Limits::from_params(&ctx.rtd.conf.params);
+        match throttle(ctx.rtd, &limits, ctx.sender, ctx.target) {
+            Some((true, notice)) => bail!("{}", notice),
+            Some((false, _)) => return Ok(vec![]),
+            None => (),
+        }
+
+        info!("[{}] RESOLVE <{}>", ctx.rtd.conf.network.name, url);
+
+        let page = match get_title(ctx.rtd, &url) {
+            Ok(page) => page,
+            Err(err) => {
+                error!("{:?}", err);
+                if !err.cached {
+                    log_failure(ctx.rtd, ctx.db, ctx.target, &url, &err);
+                }
+                return Err(err.into());
+            },
+        };
+
+        Ok(vec![render_title(ctx.rtd, &url, &page, ctx.sender, ctx.target, None)])
+    }
+}
+
+pub struct LastCommand {}
+
+impl BotCommand for LastCommand {
+    fn name(&self) -> &'static str {
+        "last"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[count] [text]"
+    }
+
+    fn help(&self) -> &'static str {
+        "list links recently posted in the channel, optionally only those \
+        whose title or URL contains some text"
+    }
+
+    fn enabled(&self, features: &Features) -> bool {
+        features.command_last
+    }
+
+    fn run(&self, ctx: &Context, args: &[String]) -> Result<Vec<String>, Error> {
+        if !ctx.is_chanmsg {
+            bail!("{} can only be used in a channel", usage(ctx.rtd, self));
+        }
+
+        if !feat!(ctx.rtd, history) {
+            bail!("history is not enabled");
+        }
+
+        let (count, text) = match args.split_first() {
+            None => (LAST_DEFAULT, &[][..]),
+            Some((first, rest)) => match first.parse::<u32>() {
+                Ok(n) if n > 0 => (n.min(LAST_MAX), rest),
+                Ok(_) => bail!("usage: {}", usage(ctx.rtd, self)),
+                Err(_) => (LAST_DEFAULT, args),
+            },
+        };
+        let text = text.join(" ");
+
+        let network = &ctx.rtd.conf.network.name;
+        let posts = if text.is_empty() {
+            ctx.db.recent_posts(network, ctx.target, count)?
+        } else {
+            ctx.db.search_posts(network, ctx.target, &text, count)?
+        };
+
+        if posts.is_empty() && text.is_empty() {
+            return Ok(vec![format!("no links posted in {}", ctx.target)]);
+        } else if posts.is_empty() {
+            return Ok(vec![format!("no links matching \"{}\" posted in {}", text, ctx.target)]);
+        }
+
+        let lines = posts
+            .into_iter()
+            .map(|p| {
+                let time = format_time(p.time());
+                let user = if feat!(ctx.rtd, mask_highlights) {
+                    create_non_highlighting_name(&p.user)
+                } else {
+                    p.user
+                };
+                format!("{} <{}> → {} {}", p.title, p.url, time, user)
+            })
+            .collect();
+
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{NewLogEntry, NewErrorEntry};
+    use crate::ratelimit::Decision;
+    use crate::sqlite::Database;
+
+    fn args(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn run(rtd: &Rtd, db: &Database, text: &str) -> Option<Result<Vec<String>, Error>> {
+        let ctx = Context {
+            rtd,
+            db,
+            sender: "testnick",
+            target: "#testchannel",
+            is_chanmsg: true,
+        };
+        dispatch(&ctx, text)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(None, parse("!", ""));
+        assert_eq!(None, parse("!", "!"));
+        assert_eq!(None, parse("!", "! help"));
+        assert_eq!(None, parse("!", "help"));
+        assert_eq!(None, parse("!", "hello !help"));
+        assert_eq!(None, parse("", "help"));
+        assert_eq!(Some(("help".into(), args(&[]))), parse("!", "!help"));
+        assert_eq!(Some(("help".into(), args(&[]))), parse("!", "  !HELP  "));
+        assert_eq!(Some(("help".into(), args(&["last"]))), parse("!", "!help last"));
+        assert_eq!(Some(("last".into(), args(&["3"]))), parse(".", ".last   3 "));
+        assert_eq!(Some(("last".into(), args(&["3"]))), parse("bot: ", "bot: last 3"));
+        assert_eq!(
+            Some(("a".into(), args(&["b c", "d", ""]))),
+            parse("!", "!a \"b c\" d \"\"")
+        );
+        assert_eq!(
+            Some(("a".into(), args(&["b c d"]))),
+            parse("!", "!a b\" c \"d")
+        );
+    }
+
+    #[test]
+    fn test_dispatch_disabled() {
+        let mut rtd = Rtd::default();
+        let db = Database::open_in_memory().unwrap();
+
+        assert!(run(&rtd, &db, "!help").is_none());
+        assert!(run(&rtd, &db, "!last").is_none());
+        assert!(run(&rtd, &db, "!title http://127.0.0.1/").is_none());
+
+        feat!(rtd, command_help) = true;
+        assert!(run(&rtd, &db, "!help").is_some());
+        assert!(run(&rtd, &db, "!last").is_none());
+        assert!(run(&rtd, &db, "!nonexistent").is_none());
+
+        param!(rtd, command_prefix) = "".to_string();
+        assert!(run(&rtd, &db, "help").is_none());
+    }
+
+    #[test]
+    fn test_help() {
+        let mut rtd = Rtd::default();
+        let db = Database::open_in_memory().unwrap();
+        feat!(rtd, command_help) = true;
+        feat!(rtd, command_last) = true;
+
+        assert_eq!(
+            vec!["commands: !help, !last (see !help [command])"],
+            run(&rtd, &db, "!help").unwrap().unwrap()
+        );
+        assert_eq!(
+            vec!["!last [count] [text]: list links recently posted in the channel, \
+                optionally only those whose title or URL contains some text"],
+            run(&rtd, &db, "!help last").unwrap().unwrap()
+        );
+        assert_eq!(
+            vec!["!last [count] [text]: list links recently posted in the channel, \
+                optionally only those whose title or URL contains some text"],
+            run(&rtd, &db, "!help !last").unwrap().unwrap()
+        );
+        assert!(run(&rtd, &db, "!help title").unwrap().is_err());
+
+        param!(rtd, command_prefix) = ".".to_string();
+        assert_eq!(
+            vec!["commands: .help, .last (see .help [command])"],
+            run(&rtd, &db, ".help").unwrap().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_title_usage() {
+        let mut rtd = Rtd::default();
+        let db = Database::open_in_memory().unwrap();
+        feat!(rtd, command_title) = true;
+
+        let err = run(&rtd, &db, "!title").unwrap().unwrap_err();
+        assert_eq!("usage: !title <url>", err.to_string());
+        assert!(run(&rtd, &db, "!title docs.rs").unwrap().is_err());
+        assert!(run(&rtd, &db, "!title ftp://127.0.0.1/").unwrap().is_err());
+
+        rtd.conf.url_filter.default = Action::Deny;
+        let err = run(&rtd, &db, "!title http://127.0.0.1/").unwrap().unwrap_err();
+        assert_eq!("that URL is not permitted", err.to_string());
+    }
+
+    #[test]
+    fn test_title_refused() {
+        let mut rtd = Rtd::default();
+        let db = Database::open_in_memory().unwrap();
+        feat!(rtd, command_title) = true;
+
+        // URLs which have been failing aren't looked up
+        for _ in 0..rtd.conf.http_params.failure_limit {
+            db.add_error(&NewErrorEntry {
+                url: "http://127.0.0.1/",
+                error_info: "error",
+                host: "127.0.0.1",
+                kind: "status",
+                status: Some(503),
+                host_failure: true,
+                network: "net",
+                channel: "#testchannel",
+            }).unwrap();
+        }
+        let err = run(&rtd, &db, "!title http://127.0.0.1/").unwrap().unwrap_err();
+        assert_eq!(
+            "that URL has been failing, so I'll skip it for a little while",
+            err.to_string()
+        );
+
+        // nor are URLs requested too quickly, which users are told of once
+        rtd.conf.http_params.failure_limit = 0;
+        param!(rtd, rate_limit_user_burst) = 1;
+        param!(rtd, rate_limit_user_refill_s) = 3600;
+        let limits = Limits::from_params(&rtd.conf.params);
+        assert_eq!(Decision::Allow, rtd.limiter.check(&limits, "testnick", "#testchannel"));
+
+        let err = run(&rtd, &db, "!title http://127.0.0.1/").unwrap().unwrap_err();
+        assert!(err.to_string().starts_with("Sorry testnick, you're posting links too quickly"));
+        assert!(run(&rtd, &db, "!title http://127.0.0.1/").unwrap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_last() {
+        let mut rtd = Rtd::default();
+        let db = Database::open_in_memory().unwrap();
+        feat!(rtd, command_last) = true;
+
+        // history must be enabled
+        assert!(run(&rtd, &db, "!last").unwrap().is_err());
+        feat!(rtd, history) = true;
+
+        assert_eq!(
+            vec!["no links posted in #testchannel"],
+            run(&rtd, &db, "!last").unwrap().unwrap()
+        );
+
+        for (n, channel) in ["#testchannel", "#other"].iter().cycle().take(30).enumerate() {
+            let title = format!("title {}", n);
+            let url = format!("http://example.com/{}", n);
+            db.add_log(&NewLogEntry {
+                title: &title,
+                url: &url,
+                url_key: &url,
+                user: "poster",
+                channel,
+                network: &rtd.conf.network.name,
+            }).unwrap();
+        }
+
+        // posts on other networks sharing the history aren't included
+        db.add_log(&NewLogEntry {
+            title: "elsewhere",
+            url: "http://example.com/",
+            url_key: "example.com",
+            user: "poster",
+            channel: "#testchannel",
+            network: "othernet",
+        }).unwrap();
+
+        let res = run(&rtd, &db, "!last").unwrap().unwrap();
+        assert_eq!(3, res.len());
+        assert!(res[0].starts_with("title 28 <http://example.com/28> → "));
+        assert!(res[0].ends_with(" poster"));
+        assert!(res[2].starts_with("title 24 "));
+
+        assert_eq!(1, run(&rtd, &db, "!last 1").unwrap().unwrap().len());
+        assert_eq!(10, run(&rtd, &db, "!last 100").unwrap().unwrap().len());
+        assert!(run(&rtd, &db, "!last 0").unwrap().is_err());
+
+        // posts may be searched for by title or URL
+        let res = run(&rtd, &db, "!last 5 TITLE 1").unwrap().unwrap();
+        assert_eq!(5, res.len());
+        assert!(res[0].starts_with("title 18 "));
+        assert!(res[4].starts_with("title 10 "));
+        assert_eq!(1, run(&rtd, &db, "!last 1 example.com/2").unwrap().unwrap().len());
+        assert_eq!(
+            vec!["no links matching \"title 1_\" posted in #testchannel"],
+            run(&rtd, &db, "!last title 1_").unwrap().unwrap()
+        );
+
+        feat!(rtd, mask_highlights) = true;
+        let res = run(&rtd, &db, "!last 1").unwrap().unwrap();
+        assert!(res[0].ends_with(" p\u{200C}oster"));
+    }
+
+    #[test]
+    fn test_last_query() {
+        let mut rtd = Rtd::default();
+        let db = Database::open_in_memory().unwrap();
+        feat!(rtd, command_last) = true;
+        feat!(rtd, history) = true;
+
+        let ctx = Context {
+            rtd: &rtd,
+            db: &db,
+            sender: "testnick",
+            target: "testnick",
+            is_chanmsg: false,
+        };
+        assert!(dispatch(&ctx, "!last").unwrap().is_err());
+    }
+}
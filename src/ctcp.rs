@@ -0,0 +1,158 @@
+/*
+ * Client-to-client protocol
+ *
+ */
+use chrono::{Local, format::{StrftimeItems, Item}};
+use failure::{Error, bail};
+use serde_derive::{Serialize, Deserialize};
+
+use crate::VERSION;
+
+const DELIM: char = '\u{1}';
+
+/// A CTCP message, i.e. `\x01COMMAND params\x01`
+#[derive(Debug, PartialEq)]
+pub struct Ctcp<'a> {
+    pub command: &'a str,
+    pub params: &'a str,
+}
+
+impl<'a> Ctcp<'a> {
+    /// Parse the text of a PRIVMSG or NOTICE, if it's a CTCP message
+    ///
+    /// The closing delimiter is optional, as some clients omit it.
+    pub fn parse(text: &'a str) -> Option<Self> {
+        let inner = text.strip_prefix(DELIM)?;
+        let inner = inner.strip_suffix(DELIM).unwrap_or(inner);
+
+        let (command, params) = inner.split_once(' ').unwrap_or((inner, ""));
+
+        if command.is_empty() {
+            return None;
+        }
+
+        Some(Self { command, params })
+    }
+
+    pub fn is_action(&self) -> bool {
+        self.command.eq_ignore_ascii_case("ACTION")
+    }
+}
+
+/// Replies to CTCP queries
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CtcpReplies {
+    /// reply to VERSION, if not given, the bot's name and version are sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// reply to SOURCE
+    pub source: String,
+    /// `strftime` format of the reply to TIME
+    pub time_format: String,
+}
+
+impl Default for CtcpReplies {
+    fn default() -> Self {
+        Self {
+            version: None,
+            source: "https://github.com/nuxeh/url-bot-rs".to_string(),
+            time_format: "%a, %d %b %Y %H:%M:%S %z".to_string(),
+        }
+    }
+}
+
+impl CtcpReplies {
+    /// Get the reply to a CTCP query, framed ready to send as a NOTICE, if
+    /// it should be answered
+    ///
+    /// Queries with an empty reply configured are not answered.
+    pub fn reply(&self, query: &Ctcp) -> Option<String> {
+        let reply = match query.command.to_ascii_uppercase().as_str() {
+            "VERSION" => self.version
+                .clone()
+                .unwrap_or_else(|| format!("url-bot-rs {}", *VERSION)),
+            "SOURCE" => self.source.clone(),
+            "TIME" if !self.time_format.is_empty() => {
+                Local::now().format(&self.time_format).to_string()
+            },
+            "PING" => query.params.to_string(),
+            "CLIENTINFO" => "ACTION CLIENTINFO PING SOURCE TIME VERSION".to_string(),
+            _ => return None,
+        };
+
+        if reply.is_empty() {
+            return None;
+        }
+
+        Some(format!("{}{} {}{}", DELIM, query.command.to_ascii_uppercase(), reply, DELIM))
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if StrftimeItems::new(&self.time_format).any(|i| i == Item::Error) {
+            bail!("invalid time_format `{}`", self.time_format);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let c = |command, params| Some(Ctcp { command, params });
+
+        assert_eq!(c("ACTION", "looks at http://example.com"),
+            Ctcp::parse("\x01ACTION looks at http://example.com\x01"));
+        assert_eq!(c("ACTION", "waves"), Ctcp::parse("\x01ACTION waves"));
+        assert_eq!(c("VERSION", ""), Ctcp::parse("\x01VERSION\x01"));
+        assert_eq!(c("PING", "123 456"), Ctcp::parse("\x01PING 123 456\x01"));
+        assert_eq!(None, Ctcp::parse("\x01\x01"));
+        assert_eq!(None, Ctcp::parse("ACTION waves"));
+        assert_eq!(None, Ctcp::parse("hi \x01ACTION waves\x01"));
+
+        assert!(Ctcp::parse("\x01action waves\x01").unwrap().is_action());
+        assert!(!Ctcp::parse("\x01VERSION\x01").unwrap().is_action());
+    }
+
+    #[test]
+    fn test_reply() {
+        let reply = |r: &CtcpReplies, q| r.reply(&Ctcp::parse(q).unwrap());
+        let mut r = CtcpReplies::default();
+
+        assert_eq!(Some(format!("\x01VERSION url-bot-rs {}\x01", *VERSION)),
+            reply(&r, "\x01VERSION\x01"));
+        assert_eq!(Some("\x01PING 1234\x01".into()), reply(&r, "\x01ping 1234\x01"));
+        assert_eq!(None, reply(&r, "\x01PING\x01"));
+        assert_eq!(Some("\x01SOURCE https://github.com/nuxeh/url-bot-rs\x01".into()),
+            reply(&r, "\x01SOURCE\x01"));
+        assert!(reply(&r, "\x01TIME\x01").unwrap().starts_with("\x01TIME "));
+        assert_eq!(None, reply(&r, "\x01ACTION waves\x01"));
+        assert_eq!(None, reply(&r, "\x01DCC SEND x\x01"));
+
+        r.version = Some("a bot".into());
+        r.source = String::new();
+        r.time_format = "%Y".into();
+        assert_eq!(Some("\x01VERSION a bot\x01".into()), reply(&r, "\x01VERSION\x01"));
+        assert_eq!(None, reply(&r, "\x01SOURCE\x01"));
+        assert_eq!(Some(format!("\x01TIME {}\x01", Local::now().format("%Y"))),
+            reply(&r, "\x01TIME\x01"));
+
+        r.version = Some(String::new());
+        r.time_format = String::new();
+        assert_eq!(None, reply(&r, "\x01VERSION\x01"));
+        assert_eq!(None, reply(&r, "\x01TIME\x01"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut r = CtcpReplies::default();
+        assert!(r.validate().is_ok());
+
+        r.time_format = "%Y %Q".into();
+        assert!(r.validate().is_err());
+    }
+}
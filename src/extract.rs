@@ -0,0 +1,385 @@
+/*
+ * URL extraction from free text
+ *
+ */
+use reqwest::Url;
+use regex::Regex;
+use lazy_static::lazy_static;
+
+use crate::tld::TLD;
+
+lazy_static! {
+    static ref SCHEME: Regex = Regex::new(r"(?i)[a-z][a-z0-9+.\-]*://").unwrap();
+    static ref UNSAFE: Regex = Regex::new(RE_UNSAFE_CHARS).unwrap();
+    static ref REPEATED_DOTS: Regex = Regex::new(r"\.\.+").unwrap();
+}
+
+// regex for unsafe characters, as defined in RFC 1738
+const RE_UNSAFE_CHARS: &str = r#"[{}|\\^~\[\]`<>"]"#;
+
+/// characters which may open a span of text wrapping a URL
+const OPENING: &[char] = &['(', '<', '[', '{', '"', '\'', '*', '_'];
+
+/// characters trimmed from the end of a URL, as sentence punctuation
+const TRAILING: &[char] = &['.', ',', ':', ';', '!', '?', '\'', '*', '_'];
+
+/// Find all URLs in a message, in the order they appear.
+///
+/// URLs are found inside running text, so that surrounding punctuation,
+/// quotes, angle brackets, and parentheses are not included in the result,
+/// e.g. `(see https://example.com/foo).` yields `https://example.com/foo`.
+/// If `partial` is set, tokens without a scheme but with a recognised TLD are
+/// also returned, with `http` added as a scheme.
+pub fn extract_urls(text: &str, partial: bool) -> Vec<Url> {
+    let mut urls = vec![];
+
+    for token in text.split_whitespace() {
+        if SCHEME.is_match(token) {
+            urls.extend(extract_from_token(token));
+        } else if partial {
+            urls.extend(extract_partial(token));
+        }
+    }
+
+    urls
+}
+
+/// find any URLs with a scheme in a single whitespace delimited token
+fn extract_from_token(token: &str) -> Vec<Url> {
+    let mut urls = vec![];
+    let mut pos = 0;
+
+    while let Some(m) = SCHEME.find_at(token, pos) {
+        let end = trim_trailing(token, m.start(), span_end(token, m.end()));
+        let span = &token[m.start()..end];
+
+        match parse_span(span) {
+            Some(url) => {
+                urls.push(url);
+                pos = end;
+            },
+            None => pos = m.end(),
+        }
+    }
+
+    urls
+}
+
+/// find a URL without a scheme, in a single whitespace delimited token
+fn extract_partial(token: &str) -> Option<Url> {
+    let start = token.len() - token.trim_start_matches(OPENING).len();
+    let end = trim_trailing(token, start, span_end(token, start));
+
+    add_scheme_for_tld(&token[start..end])
+        .and_then(|u| parse_span(&u))
+}
+
+/// parse a candidate span, rejecting anything which isn't a usable URL
+fn parse_span(span: &str) -> Option<Url> {
+    if contains_unsafe_chars(span) {
+        return None;
+    }
+
+    match span.parse::<Url>() {
+        Ok(url) if url.has_host() => Some(url),
+        _ => None,
+    }
+}
+
+/// Find the end of a URL span starting at byte offset `start`.
+///
+/// The span ends at whitespace, control characters, angle brackets and
+/// quotation marks, none of which may appear unescaped in a URL, or at a closing
+/// bracket which has no matching opening bracket within the span, since in
+/// that case the bracket wraps the URL rather than being part of it.
+fn span_end(text: &str, start: usize) -> usize {
+    let mut parens = 0;
+    let mut brackets = 0;
+    let mut braces = 0;
+    let mut end = start;
+
+    for (i, c) in text[start..].char_indices() {
+        match c {
+            c if c.is_whitespace() || c.is_control() => break,
+            '<' | '>' | '"' => break,
+            '“' | '”' | '‘' | '’' | '«' | '»' => break,
+            '(' => parens += 1,
+            '[' => brackets += 1,
+            '{' => braces += 1,
+            ')' if parens == 0 => break,
+            ']' if brackets == 0 => break,
+            '}' if braces == 0 => break,
+            ')' => parens -= 1,
+            ']' => brackets -= 1,
+            '}' => braces -= 1,
+            _ => (),
+        }
+        end = start + i + c.len_utf8();
+    }
+
+    end
+}
+
+/// Trim sentence punctuation from the end of a span.
+///
+/// Unclosed opening brackets are also removed, since a URL can't usefully end
+/// with one.
+fn trim_trailing(text: &str, start: usize, end: usize) -> usize {
+    let span = text[start..end]
+        .trim_end_matches(|c| TRAILING.contains(&c) || "([{".contains(c));
+
+    start + span.len()
+}
+
+/// does the token contain characters not permitted by RFC 1738
+fn contains_unsafe_chars(token: &str) -> bool {
+    UNSAFE.is_match(token)
+}
+
+/// if a token has a recognised TLD, but no scheme, add one
+pub fn add_scheme_for_tld(token: &str) -> Option<String> {
+    if token.parse::<Url>().is_err() {
+        if token.starts_with(|s: char| !s.is_alphabetic()) {
+            return None;
+        }
+
+        if REPEATED_DOTS.is_match(token) {
+            return None;
+        }
+
+        let new_token = format!("http://{}", token);
+
+        if let Ok(url) = new_token.parse::<Url>() {
+            if !url.domain()?.contains('.') {
+                return None;
+            }
+
+            // reject email addresses
+            if !url.username().is_empty() {
+                return None;
+            }
+
+            let tld = url.domain()?
+                .split('.')
+                .next_back()?;
+
+            if TLD.contains(tld) {
+                return Some(new_token);
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (message text, URLs expected to be found)
+    const CORPUS: &[(&str, &[&str])] = &[
+        // no URLs
+        ("", &[]),
+        ("   ", &[]),
+        ("hello world", &[]),
+        ("http", &[]),
+        ("http://", &[]),
+        ("https:// example.com", &[]),
+        ("foo:bar", &[]),
+        ("mailto:someone@example.com", &[]),
+        ("urn:isbn:0451450523", &[]),
+        ("docs.rs", &[]),
+        // bare URLs
+        ("https://example.com", &["https://example.com/"]),
+        ("https://example.com/", &["https://example.com/"]),
+        ("http://example.com/foo", &["http://example.com/foo"]),
+        ("HTTPS://EXAMPLE.COM/Foo", &["https://example.com/Foo"]),
+        ("https://example.com:8080/a/b", &["https://example.com:8080/a/b"]),
+        ("http://127.0.0.1:28382/", &["http://127.0.0.1:28382/"]),
+        ("https://example.com/a?b=c&d=e", &["https://example.com/a?b=c&d=e"]),
+        ("https://example.com/#frag", &["https://example.com/#frag"]),
+        ("https://user@example.com/", &["https://user@example.com/"]),
+        ("https://example.com/it's", &["https://example.com/it's"]),
+        ("https://example.com/a,b", &["https://example.com/a,b"]),
+        ("https://example.com/a.b", &["https://example.com/a.b"]),
+        ("https://example.com/a:b", &["https://example.com/a:b"]),
+        // other schemes are extracted, but filtered by the caller
+        ("ftp://example.com/file", &["ftp://example.com/file"]),
+        ("git://example.com/repo", &["git://example.com/repo"]),
+        // surrounding text
+        ("see https://a.bc/d", &["https://a.bc/d"]),
+        ("https://a.bc/d is good", &["https://a.bc/d"]),
+        ("one https://a.bc/1 two https://a.bc/2", &["https://a.bc/1", "https://a.bc/2"]),
+        ("same https://a.bc/ https://a.bc/", &["https://a.bc/", "https://a.bc/"]),
+        // trailing punctuation
+        ("see https://a.bc/d.", &["https://a.bc/d"]),
+        ("see https://a.bc/d,", &["https://a.bc/d"]),
+        ("see https://a.bc/d!", &["https://a.bc/d"]),
+        ("see https://a.bc/d?", &["https://a.bc/d"]),
+        ("see https://a.bc/d:", &["https://a.bc/d"]),
+        ("see https://a.bc/d;", &["https://a.bc/d"]),
+        ("see https://a.bc/d...", &["https://a.bc/d"]),
+        ("see https://a.bc/d?!", &["https://a.bc/d"]),
+        ("https://a.bc/d, https://a.bc/e.", &["https://a.bc/d", "https://a.bc/e"]),
+        ("see https://a.bc/?q=1.", &["https://a.bc/?q=1"]),
+        // parentheses
+        ("(https://a.bc/d)", &["https://a.bc/d"]),
+        ("(see https://a.bc/d)", &["https://a.bc/d"]),
+        ("(see https://a.bc/d).", &["https://a.bc/d"]),
+        ("(https://a.bc/d), maybe", &["https://a.bc/d"]),
+        ("https://en.wikipedia.org/wiki/Rust_(programming_language)",
+            &["https://en.wikipedia.org/wiki/Rust_(programming_language)"]),
+        ("(https://en.wikipedia.org/wiki/Rust_(programming_language))",
+            &["https://en.wikipedia.org/wiki/Rust_(programming_language)"]),
+        ("see (https://en.wikipedia.org/wiki/Rust_(programming_language)).",
+            &["https://en.wikipedia.org/wiki/Rust_(programming_language)"]),
+        ("https://a.bc/d(", &["https://a.bc/d"]),
+        ("https://a.bc/d)", &["https://a.bc/d"]),
+        ("((https://a.bc/d))", &["https://a.bc/d"]),
+        // angle brackets
+        ("<https://x.org>", &["https://x.org/"]),
+        ("<https://x.org/a/b>", &["https://x.org/a/b"]),
+        ("link: <https://x.org/a>.", &["https://x.org/a"]),
+        ("<https://x.org/a><https://x.org/b>", &["https://x.org/a", "https://x.org/b"]),
+        // quotes
+        ("\"https://a.bc/d\"", &["https://a.bc/d"]),
+        ("'https://a.bc/d'", &["https://a.bc/d"]),
+        ("he said \"https://a.bc/d\".", &["https://a.bc/d"]),
+        ("“https://a.bc/d”", &["https://a.bc/d"]),
+        ("«https://a.bc/d»", &["https://a.bc/d"]),
+        // markdown style links
+        ("[text](https://a.bc/d)", &["https://a.bc/d"]),
+        ("[https://a.bc/d](https://a.bc/e)", &["https://a.bc/d", "https://a.bc/e"]),
+        ("[link](https://a.bc/d_(e)).", &["https://a.bc/d_(e)"]),
+        ("*https://a.bc/d*", &["https://a.bc/d"]),
+        ("_https://a.bc/d_", &["https://a.bc/d"]),
+        ("[https://a.bc/d]", &["https://a.bc/d"]),
+        // unsafe characters reject the URL
+        ("https://a.bc/{}", &[]),
+        ("https://a.bc/a|b", &[]),
+        ("https://a.bc/~user", &[]),
+        ("https://a.bc/[x]", &[]),
+        ("https://a.bc/a`b", &[]),
+        ("https://a.bc/a^b", &[]),
+        ("https://a.bc/a\\b", &[]),
+        // control characters end a URL
+        ("https://a.bc/d\x01", &["https://a.bc/d"]),
+        ("\x02https://a.bc/d\x02", &["https://a.bc/d"]),
+        // non-ASCII
+        ("https://a.bc/ü", &["https://a.bc/%C3%BC"]),
+        ("https://bücher.de/", &["https://xn--bcher-kva.de/"]),
+        ("→https://a.bc/d←", &["https://a.bc/d%E2%86%90"]),
+    ];
+
+    /// as above, with partial URLs enabled
+    const CORPUS_PARTIAL: &[(&str, &[&str])] = &[
+        ("docs.rs", &["http://docs.rs/"]),
+        ("see docs.rs.", &["http://docs.rs/"]),
+        ("(docs.rs)", &["http://docs.rs/"]),
+        ("<docs.rs>", &["http://docs.rs/"]),
+        ("'docs.rs'", &["http://docs.rs/"]),
+        ("\"docs.rs\",", &["http://docs.rs/"]),
+        ("docs.rs/foo", &["http://docs.rs/foo"]),
+        ("docs.rs https://a.bc/d", &["http://docs.rs/", "https://a.bc/d"]),
+        ("https://a.bc/d docs.rs", &["https://a.bc/d", "http://docs.rs/"]),
+        ("abc.cheese", &[]),
+        ("test@gmail.com", &[]),
+        ("(test@gmail.com)", &[]),
+        ("e.g.", &[]),
+        ("wow...cool", &[]),
+        ("...", &[]),
+        ("()", &[]),
+    ];
+
+    fn check(text: &str, partial: bool, expected: &[&str]) {
+        let found: Vec<String> = extract_urls(text, partial)
+            .iter()
+            .map(|u| u.as_str().to_string())
+            .collect();
+        assert_eq!(expected, &found[..], "extracting from {:?}", text);
+    }
+
+    #[test]
+    fn test_extract_urls() {
+        for (text, expected) in CORPUS {
+            check(text, false, expected);
+        }
+    }
+
+    #[test]
+    fn test_extract_urls_partial() {
+        for (text, expected) in CORPUS_PARTIAL {
+            check(text, true, expected);
+        }
+
+        // without partial URLs enabled, nothing is found
+        for (text, expected) in CORPUS_PARTIAL {
+            let with_scheme = expected.iter().filter(|u| u.starts_with("https"));
+            assert_eq!(
+                extract_urls(text, false).len(),
+                with_scheme.count()
+            );
+        }
+    }
+
+    #[test]
+    fn test_contains_unsafe_chars() {
+        for c in &['{', '}', '|', '\\', '^', '~', '[', ']', '`', '<', '>', '"']
+        {
+            assert!(contains_unsafe_chars(&format!("http://z/{}", c)));
+        }
+        assert_eq!(contains_unsafe_chars("http://z.zzz/"), false);
+    }
+
+    #[test]
+    fn test_add_scheme_for_tld() {
+        // appears to be a URL, and has a valid TLD
+        assert!(add_scheme_for_tld("docs.rs").is_some());
+        assert!(add_scheme_for_tld("nomnomnom.xyz").is_some());
+        assert!(add_scheme_for_tld("endless.horse").is_some());
+        assert!(add_scheme_for_tld("google.co.uk").is_some());
+        assert!(add_scheme_for_tld("notreal.co.uk/#banana").is_some());
+        assert!(add_scheme_for_tld("notreal.co.uk/?banana=3").is_some());
+
+        // return value is as expected
+        assert_eq!(
+            Some(String::from("http://nomnomnom.xyz")),
+            add_scheme_for_tld("nomnomnom.xyz")
+        );
+        assert_eq!(
+            Some(String::from("http://google.co.uk")),
+            add_scheme_for_tld("google.co.uk")
+        );
+
+        // already a valid URL
+        assert!(add_scheme_for_tld("http://nomnomnom.xyz").is_none());
+        assert!(add_scheme_for_tld("http://endless.horse").is_none());
+
+        // not a recognised TLD
+        assert!(add_scheme_for_tld("abc.cheese").is_none());
+        assert!(add_scheme_for_tld("abc.limes").is_none());
+
+        // recognised TLD, but incomplete as a URL
+        assert!(add_scheme_for_tld("xyz").is_none());
+        assert!(add_scheme_for_tld("uk").is_none());
+        assert!(add_scheme_for_tld("horse").is_none());
+
+        // don't resolve email addresses
+        assert!(add_scheme_for_tld("test@gmail.com").is_none());
+        assert!(add_scheme_for_tld("word.word@gmail.com").is_none());
+
+        // don't resolve tokens beinning with @
+        assert!(add_scheme_for_tld("@gmail.com").is_none());
+        assert!(add_scheme_for_tld("@endless.horse").is_none());
+
+        // don't resolve tokens beginning with '.'
+        assert!(add_scheme_for_tld(".net").is_none());
+        assert!(add_scheme_for_tld(".zip").is_none());
+        assert!(add_scheme_for_tld("...cool").is_none());
+
+        // don't resolve tokens containing repeated full-stops
+        assert_eq!(None, add_scheme_for_tld("wow...cool"));
+        assert_eq!(None, add_scheme_for_tld("something..wow"));
+        assert_eq!(None, add_scheme_for_tld("something.....boo"));
+    }
+}
@@ -0,0 +1,208 @@
+/*
+ * URL filtering
+ *
+ */
+use reqwest::Url;
+use failure::{Error, bail};
+use serde_derive::{Serialize, Deserialize};
+
+use crate::config::ConfRegex;
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// A URL filter rule, which matches a URL if all of its conditions match
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub action: Action,
+    /// host name, either exact, or matching any subdomain if given as
+    /// `*.example.com`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// path prefix, e.g. `/private/`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// regular expression matched against the full URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<ConfRegex>,
+}
+
+impl Rule {
+    fn matches(&self, url: &Url) -> bool {
+        self.host.as_ref().is_none_or(|h| url.host_str().is_some_and(|u| host_matches(h, u)))
+            && self.path.as_ref().is_none_or(|p| url.path().starts_with(p.as_str()))
+            && self.regex.as_ref().is_none_or(|r| r.0.is_match(url.as_str()))
+    }
+}
+
+/// Match a host name against a pattern, either exact, or matching any
+/// subdomain if given as `*.example.com`
+pub(crate) fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+    let pattern = pattern.trim_end_matches('.').to_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.ends_with(&format!(".{}", domain)),
+        None => host == pattern,
+    }
+}
+
+/// Rules determining which URLs may be looked up
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct UrlFilter {
+    /// action for URLs not matched by any rule
+    pub default: Action,
+    /// response for filtered URLs, no response is made if empty
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+}
+
+impl UrlFilter {
+    /// Decide whether a URL may be looked up
+    ///
+    /// Deny rules take precedence over allow rules, which take precedence
+    /// over the default action.
+    pub fn check(&self, url: &Url) -> Action {
+        let matching = |a| self.rules.iter().any(|r| r.action == a && r.matches(url));
+
+        if matching(Action::Deny) {
+            Action::Deny
+        } else if matching(Action::Allow) {
+            Action::Allow
+        } else {
+            self.default
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        for (n, rule) in self.rules.iter().enumerate() {
+            if rule.host.is_none() && rule.path.is_none() && rule.regex.is_none() {
+                bail!("rule {}: no host, path, or regex given", n + 1);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Action::*;
+
+    fn filter(toml: &str) -> UrlFilter {
+        toml::de::from_str(toml).unwrap()
+    }
+
+    fn check(f: &UrlFilter, url: &str) -> Action {
+        f.check(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_default() {
+        let f = UrlFilter::default();
+        assert_eq!(Allow, check(&f, "http://example.com/"));
+
+        let f = filter("default = \"deny\"");
+        assert_eq!(Deny, check(&f, "http://example.com/"));
+    }
+
+    #[test]
+    fn test_host() {
+        let f = filter(r#"
+            [[rules]]
+            action = "deny"
+            host = "Example.com"
+
+            [[rules]]
+            action = "deny"
+            host = "*.internal.example.org"
+        "#);
+
+        assert_eq!(Deny, check(&f, "http://example.com/"));
+        assert_eq!(Deny, check(&f, "https://EXAMPLE.com./page"));
+        assert_eq!(Allow, check(&f, "http://www.example.com/"));
+        assert_eq!(Allow, check(&f, "http://example.com.au/"));
+        assert_eq!(Deny, check(&f, "http://a.internal.example.org/"));
+        assert_eq!(Deny, check(&f, "http://a.b.internal.example.org/"));
+        assert_eq!(Allow, check(&f, "http://internal.example.org/"));
+        assert_eq!(Allow, check(&f, "http://xinternal.example.org/"));
+    }
+
+    #[test]
+    fn test_path_and_regex() {
+        let f = filter(r#"
+            [[rules]]
+            action = "deny"
+            host = "example.com"
+            path = "/private/"
+
+            [[rules]]
+            action = "deny"
+            regex = "(?i)\\.exe$"
+        "#);
+
+        assert_eq!(Deny, check(&f, "http://example.com/private/page"));
+        assert_eq!(Allow, check(&f, "http://example.com/public/page"));
+        assert_eq!(Allow, check(&f, "http://example.org/private/page"));
+        assert_eq!(Deny, check(&f, "http://example.org/setup.EXE"));
+        assert_eq!(Allow, check(&f, "http://example.org/setup.exe?x"));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let f = filter(r#"
+            default = "deny"
+
+            [[rules]]
+            action = "allow"
+            host = "*.example.com"
+
+            [[rules]]
+            action = "deny"
+            host = "bad.example.com"
+        "#);
+
+        assert_eq!(Allow, check(&f, "http://www.example.com/"));
+        assert_eq!(Deny, check(&f, "http://bad.example.com/"));
+        assert_eq!(Deny, check(&f, "http://example.org/"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(toml::de::from_str::<UrlFilter>("default = \"maybe\"").is_err());
+        assert!(toml::de::from_str::<UrlFilter>(
+            "[[rules]]\naction = \"deny\"\nregex = \"(\""
+        ).is_err());
+        assert!(toml::de::from_str::<UrlFilter>(
+            "[[rules]]\nhost = \"example.com\""
+        ).is_err());
+
+        let f = filter("[[rules]]\naction = \"deny\"");
+        assert!(f.validate().is_err());
+        let f = filter("[[rules]]\naction = \"deny\"\npath = \"/\"");
+        assert!(f.validate().is_ok());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let f = filter(r#"
+            message = "not here"
+
+            [[rules]]
+            action = "allow"
+            regex = "^https://"
+        "#);
+
+        let f: UrlFilter = toml::de::from_str(&toml::ser::to_string(&f).unwrap()).unwrap();
+        assert_eq!("not here", f.message);
+        assert_eq!("^https://", f.rules[0].regex.as_ref().unwrap().0.as_str());
+    }
+}
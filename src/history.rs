@@ -0,0 +1,478 @@
+/*
+ * Storage of the history of posts, and of failed lookups
+ *
+ */
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+use failure::{Error, bail};
+use log::{info, error};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde_derive::{Serialize, Deserialize};
+
+use crate::{
+    config::{Rtd, DbType},
+    sqlite::Database,
+};
+#[cfg(feature = "postgres")]
+use crate::postgres::Postgres;
+
+/// A store of the history of posts, and of failures to look up URLs, which
+/// may be shared between threads
+pub trait HistoryStore: Send + Sync {
+    /// record a post of a URL
+    fn add_log(&self, entry: &NewLogEntry) -> Result<(), Error>;
+
+    /// find earlier posts of a URL, by its canonical key, or for posts
+    /// logged without a key, by the URL itself, optionally only those on a
+    /// network, in a channel, or since a time
+    fn check_prepost(
+        &self,
+        key: &str,
+        url: &str,
+        network: Option<&str>,
+        channel: Option<&str>,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Option<PrevPost>, Error>;
+
+    /// find earlier posts of a URL, as `check_prepost` does, and record this
+    /// post of it, at once, so that of two posts of a URL made at the same
+    /// time, by any instance of the bot, one is always seen as the earlier
+    fn check_and_add_log(
+        &self,
+        entry: &NewLogEntry,
+        network: Option<&str>,
+        channel: Option<&str>,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Option<PrevPost>, Error>;
+
+    /// get the most recent posts made in a channel of a network, newest first
+    fn recent_posts(&self, network: &str, channel: &str, limit: u32)
+        -> Result<Vec<LogEntry>, Error>;
+
+    /// get the most recent posts made in a channel of a network whose title or
+    /// URL contains some text, ignoring case, newest first
+    fn search_posts(&self, network: &str, channel: &str, text: &str, limit: u32)
+        -> Result<Vec<LogEntry>, Error>;
+
+    /// remove posts made before a time, moving them to the archive if
+    /// required, and errors recorded before it, returning the number of posts
+    /// removed
+    fn prune(&self, before: DateTime<Utc>, archive: bool) -> Result<usize, Error>;
+
+    /// remove errors recorded before a time, returning the number removed
+    fn prune_errors(&self, before: DateTime<Utc>) -> Result<usize, Error>;
+
+    /// record a failure to look up a URL
+    fn add_error(&self, entry: &NewErrorEntry) -> Result<(), Error>;
+
+    /// count failures to look up a URL, and failures of its host which are
+    /// likely to affect any URL, since a time
+    fn failure_counts(&self, url: &str, host: &str, since: DateTime<Utc>)
+        -> Result<FailureCounts, Error>;
+
+    /// get the hosts with the most failures since a time, most first
+    fn failing_hosts(&self, since: DateTime<Utc>, limit: u32)
+        -> Result<Vec<HostFailures>, Error>;
+}
+
+/// how often the pruning thread checks whether the store is still open
+const PRUNE_POLL: Duration = Duration::from_secs(60);
+
+/// History stores which are open and shared, so that networks which share a
+/// store use the same one, which is closed once none of them are using it
+#[derive(Clone, Default)]
+pub struct Stores {
+    open: Arc<Mutex<HashMap<String, OpenStore>>>,
+}
+
+/// A shared store, and how it's pruned
+struct OpenStore {
+    store: Weak<dyn HistoryStore>,
+    pruning: Pruning,
+}
+
+impl Stores {
+    /// Open the history store selected by the configuration, or if it's
+    /// shared, and another network has already opened it, get that
+    ///
+    /// If history is disabled, no store is opened, and nothing is recorded.
+    ///
+    /// Each store which is persisted is pruned by a single thread, started
+    /// when it's opened, so networks sharing a store must have the same
+    /// settings for pruning it.
+    pub fn open(&self, rtd: &Rtd) -> Result<Arc<dyn HistoryStore>, Error> {
+        if !rtd.conf.features.history {
+            return Ok(Arc::new(NoHistory));
+        }
+
+        let pruning = Pruning::new(rtd);
+        // stores in memory are lost when the bot stops, so aren't pruned
+        let persisted = rtd.conf.database.db_type != DbType::InMemory;
+
+        if !rtd.conf.database.shared {
+            let store = open(rtd)?;
+            if persisted {
+                pruning.spawn(&store, &rtd.conf.network.name);
+            }
+            return Ok(store);
+        }
+
+        let location = match rtd.conf.database.db_type {
+            DbType::InMemory => "in-memory".to_string(),
+            DbType::Sqlite => format!("sqlite:{:?}", rtd.paths.db),
+            #[cfg(feature = "postgres")]
+            DbType::Postgres => format!("postgres:{:?}", rtd.conf.database.url),
+        };
+
+        let mut stores = self.open.lock().unwrap();
+
+        if let Some(open) = stores.get(&location) {
+            if let Some(store) = open.store.upgrade() {
+                if pruning != open.pruning {
+                    bail!("the shared database is pruned with different settings by \
+                        another network: {:?}", open.pruning);
+                }
+                return Ok(store);
+            }
+        }
+
+        let store = open(rtd)?;
+        if persisted {
+            pruning.clone().spawn(&store, &rtd.conf.network.name);
+        }
+        stores.retain(|_, o| o.store.strong_count() > 0);
+        stores.insert(location, OpenStore { store: Arc::downgrade(&store), pruning });
+
+        Ok(store)
+    }
+}
+
+/// The history store used when history is disabled, which records nothing, so
+/// that no post is seen as a repost, and no URL as failing
+struct NoHistory;
+
+impl HistoryStore for NoHistory {
+    fn add_log(&self, _: &NewLogEntry) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_prepost(
+        &self,
+        _: &str,
+        _: &str,
+        _: Option<&str>,
+        _: Option<&str>,
+        _: Option<DateTime<Utc>>,
+    ) -> Result<Option<PrevPost>, Error> {
+        Ok(None)
+    }
+
+    fn check_and_add_log(
+        &self,
+        _: &NewLogEntry,
+        _: Option<&str>,
+        _: Option<&str>,
+        _: Option<DateTime<Utc>>,
+    ) -> Result<Option<PrevPost>, Error> {
+        Ok(None)
+    }
+
+    fn recent_posts(&self, _: &str, _: &str, _: u32) -> Result<Vec<LogEntry>, Error> {
+        Ok(vec![])
+    }
+
+    fn search_posts(&self, _: &str, _: &str, _: &str, _: u32) -> Result<Vec<LogEntry>, Error> {
+        Ok(vec![])
+    }
+
+    fn prune(&self, _: DateTime<Utc>, _: bool) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    fn prune_errors(&self, _: DateTime<Utc>) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    fn add_error(&self, _: &NewErrorEntry) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn failure_counts(&self, _: &str, _: &str, _: DateTime<Utc>) -> Result<FailureCounts, Error> {
+        Ok(FailureCounts::default())
+    }
+
+    fn failing_hosts(&self, _: DateTime<Utc>, _: u32) -> Result<Vec<HostFailures>, Error> {
+        Ok(vec![])
+    }
+}
+
+/// Settings for removing old posts, and old failures, from a store
+#[derive(Debug, Clone, PartialEq)]
+struct Pruning {
+    retention_days: u32,
+    archive: bool,
+    interval_s: u64,
+    failure_window_s: u64,
+}
+
+impl Pruning {
+    fn new(rtd: &Rtd) -> Self {
+        let conf = &rtd.conf.database;
+
+        Self {
+            retention_days: conf.retention_days,
+            archive: conf.archive,
+            interval_s: conf.prune_interval_s,
+            failure_window_s: rtd.conf.http_params.failure_window_s,
+        }
+    }
+
+    /// Remove posts older than the retention period, if there is one, and
+    /// failures older than the window they're counted in, from a store,
+    /// periodically, until it's closed.
+    fn spawn(self, store: &Arc<dyn HistoryStore>, net: &str) {
+        if self.interval_s == 0 {
+            return;
+        }
+
+        let store = Arc::downgrade(store);
+        let net = net.to_string();
+        let retention = Some(chrono::Duration::days(self.retention_days.into()))
+            .filter(|_| self.retention_days > 0);
+        let window = chrono::Duration::seconds(self.failure_window_s as i64);
+        let interval = Duration::from_secs(self.interval_s);
+
+        thread::spawn(move || {
+            let mut last: Option<Instant> = None;
+
+            while let Some(store) = store.upgrade() {
+                if last.is_none_or(|t| t.elapsed() >= interval) {
+                    if let Some(retention) = retention {
+                        match store.prune(Utc::now() - retention, self.archive) {
+                            Ok(0) => (),
+                            Ok(n) => info!("[{}] pruned {} posts from history", net, n),
+                            Err(e) => error!("[{}] failed to prune history: {}", net, e),
+                        }
+                    }
+                    if let Err(e) = store.prune_errors(Utc::now() - window) {
+                        error!("[{}] failed to prune failures: {}", net, e);
+                    }
+                    last = Some(Instant::now());
+                }
+
+                drop(store);
+                thread::sleep(PRUNE_POLL.min(interval));
+            }
+        });
+    }
+}
+
+/// open the history store selected by the configuration
+fn open(rtd: &Rtd) -> Result<Arc<dyn HistoryStore>, Error> {
+    let conf = &rtd.conf.database;
+
+    Ok(match conf.db_type {
+        DbType::InMemory => Arc::new(Database::open_in_memory()?),
+        DbType::Sqlite => match rtd.paths.db {
+            Some(ref path) => Arc::new(Database::open(path)?),
+            None => bail!("no path for the sqlite database"),
+        },
+        #[cfg(feature = "postgres")]
+        DbType::Postgres => match conf.url {
+            Some(ref url) => Arc::new(Postgres::connect(url, conf.tls_ca_file.as_deref())?),
+            None => bail!("no url for the postgres database"),
+        },
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct NewLogEntry<'a> {
+    pub title: &'a str,
+    pub url: &'a str,
+    /// canonical key of the URL, see `canonical::Canonical::key`
+    pub url_key: &'a str,
+    pub user: &'a str,
+    pub channel: &'a str,
+    pub network: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogEntry {
+    pub title: String,
+    pub url: String,
+    pub user: String,
+    pub channel: String,
+    /// UTC Unix timestamp
+    pub time_created: i64,
+}
+
+impl LogEntry {
+    /// get when the URL was posted
+    pub fn time(&self) -> DateTime<Utc> {
+        timestamp(self.time_created)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct NewErrorEntry<'a> {
+    pub url: &'a str,
+    /// the error, as reported
+    pub error_info: &'a str,
+    pub host: &'a str,
+    /// kind of failure, see `http::FailureKind`
+    pub kind: &'a str,
+    /// HTTP status, if there was a response
+    pub status: Option<u16>,
+    /// whether the failure is likely to affect any URL of the host
+    pub host_failure: bool,
+    pub network: &'a str,
+    pub channel: &'a str,
+}
+
+/// Counts of recent failures of a URL and its host
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub struct FailureCounts {
+    pub url: u32,
+    pub host: u32,
+}
+
+/// Recent failures of a host
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct HostFailures {
+    pub host: String,
+    pub count: u32,
+    /// kind of the most recent failure
+    pub last_kind: Option<String>,
+}
+
+/// Earlier posts of a URL
+#[derive(Debug, Default, Deserialize)]
+pub struct PrevPost {
+    /// number of times the URL has been posted
+    pub count: u32,
+    /// the first post
+    pub user: String,
+    pub time_created: i64,
+    pub channel: String,
+    /// network, unless it was posted before networks were recorded
+    pub network: Option<String>,
+    /// the most recent post
+    pub last_user: String,
+    pub last_time_created: i64,
+    pub last_channel: String,
+    pub last_network: Option<String>,
+}
+
+impl PrevPost {
+    /// get when the URL was first posted
+    pub fn time(&self) -> DateTime<Utc> {
+        timestamp(self.time_created)
+    }
+
+    /// get when the URL was most recently posted
+    pub fn last_time(&self) -> DateTime<Utc> {
+        timestamp(self.last_time_created)
+    }
+}
+
+/// format in which the time of posts is displayed, and was stored by SQLite
+/// databases before schema version 1
+pub(crate) const TIME_FORMAT: &str = "%a %b %-d %H:%M:%S %-Y";
+
+/// format the time of a post for display
+pub fn format_time(time: DateTime<Utc>) -> String {
+    time.format(TIME_FORMAT).to_string()
+}
+
+/// a `LIKE` pattern matching text containing some text, escaped with `\`
+pub(crate) fn like_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+
+    format!("%{}%", escaped)
+}
+
+fn timestamp(secs: i64) -> DateTime<Utc> {
+    DateTime::from_utc(NaiveDateTime::from_timestamp(secs, 0), Utc)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Conf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_open() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        let entry = NewLogEntry {
+            title: "t",
+            url: "http://example.com",
+            url_key: "example.com",
+            user: "a",
+            channel: "#c",
+            network: "net",
+        };
+
+        let mut conf = Conf::default();
+        conf.database.db_type = DbType::Sqlite;
+        // nothing is recorded if history is disabled
+        let rtd = Rtd::new().conf(conf.clone()).db(Some(&path));
+        let store = rtd.stores.open(&rtd).unwrap();
+        store.add_log(&entry).unwrap();
+        assert!(store.recent_posts("net", "#c", 10).unwrap().is_empty());
+        assert!(!path.exists());
+
+        conf.features.history = true;
+        let rtd = rtd.conf(conf.clone());
+        rtd.stores.open(&rtd).unwrap().add_log(&entry).unwrap();
+        assert!(path.exists());
+        let posts = rtd.stores.open(&rtd).unwrap().recent_posts("net", "#c", 10).unwrap();
+        assert_eq!(1, posts.len());
+
+        #[cfg(feature = "postgres")]
+        {
+            conf.database.db_type = DbType::Postgres;
+            let rtd = Rtd::new().conf(conf);
+            assert!(rtd.stores.open(&rtd).is_err());
+        }
+    }
+
+    #[test]
+    fn test_open_shared() {
+        let stores = Stores::default();
+        let rtd = |net: &str, shared| {
+            let mut conf = Conf::default();
+            conf.network.name = net.into();
+            conf.features.history = true;
+            conf.database.shared = shared;
+            Rtd::new().conf(conf).stores(stores.clone())
+        };
+        let (foo, bar, baz) = (rtd("foo", true), rtd("bar", true), rtd("baz", false));
+
+        let store = foo.stores.open(&foo).unwrap();
+        assert!(Arc::ptr_eq(&store, &bar.stores.open(&bar).unwrap()));
+        assert!(!Arc::ptr_eq(&store, &baz.stores.open(&baz).unwrap()));
+
+        // stores are closed when no network is using them
+        let weak = Arc::downgrade(&store);
+        drop(store);
+        assert!(weak.upgrade().is_none());
+        let store = bar.stores.open(&bar).unwrap();
+        assert!(store.check_prepost("a", "a", None, None, None).unwrap().is_none());
+        // networks sharing a store must prune it in the same way
+        let mut qux = rtd("qux", true);
+        qux.conf.database.retention_days = 30;
+        assert!(qux.stores.open(&qux).is_err());
+        drop(store);
+        assert!(qux.stores.open(&qux).is_ok());
+    }
+}
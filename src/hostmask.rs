@@ -0,0 +1,157 @@
+/*
+ * Hostmask matching
+ *
+ */
+use irc::client::prelude::*;
+use irc::proto::message::Tag;
+
+use crate::config::irc_lowercase;
+
+/// The sender of a message, as identified by its prefix and IRCv3 tags
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Source<'a> {
+    pub nick: &'a str,
+    /// full `nick!user@host` hostmask
+    pub mask: Option<&'a str>,
+    /// services account, from the `account` tag
+    pub account: Option<&'a str>,
+    /// whether the sender is marked as a bot, by the `bot` tag
+    pub is_bot: bool,
+}
+
+impl<'a> Source<'a> {
+    /// Get the source of a message, if it was sent by a user
+    pub fn new(message: &'a Message) -> Option<Self> {
+        let nick = message.source_nickname()?;
+
+        Some(Self {
+            nick,
+            mask: message.prefix.as_deref().filter(|p| p.contains('!')),
+            account: tag(message, "account").filter(|a| *a != "*"),
+            is_bot: has_tag(message, "bot") || has_tag(message, "draft/bot"),
+        })
+    }
+
+    /// Check whether the source matches any of a list of hostmask patterns
+    pub fn matches_any(&self, patterns: &[String]) -> bool {
+        self.mask.is_some_and(|m| patterns.iter().any(|p| matches(p, m)))
+    }
+
+    /// Check whether the source is logged in to any of a list of accounts
+    pub fn account_in(&self, accounts: &[String]) -> bool {
+        self.account.is_some_and(|a| {
+            let a = irc_lowercase(a);
+            accounts.iter().any(|c| irc_lowercase(c) == a)
+        })
+    }
+}
+
+fn find_tag<'a>(message: &'a Message, name: &str) -> Option<&'a Tag> {
+    message.tags.as_ref()?.iter().find(|Tag(k, _)| k == name)
+}
+
+fn has_tag(message: &Message, name: &str) -> bool {
+    find_tag(message, name).is_some()
+}
+
+/// Get the value of a message tag
+fn tag<'a>(message: &'a Message, name: &str) -> Option<&'a str> {
+    find_tag(message, name).and_then(|Tag(_, v)| v.as_deref())
+}
+
+/// Match a hostmask (`nick!user@host`) against a pattern, where `*` matches
+/// any sequence of characters, and `?` matches any single character.
+///
+/// Comparison is case-insensitive, using RFC 1459 case mapping.
+pub fn matches(pattern: &str, mask: &str) -> bool {
+    let pattern: Vec<char> = irc_lowercase(pattern).chars().collect();
+    let mask: Vec<char> = irc_lowercase(mask).chars().collect();
+
+    glob(&pattern, &mask)
+}
+
+/// Match a glob pattern, backtracking to the most recent `*` on mismatch
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match star {
+                Some((sp, st)) => {
+                    p = sp + 1;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("nick!user@host", "nick!user@host"));
+        assert!(matches("NICK!user@HOST", "nick!USER@host"));
+        assert!(matches("[nick]!*@*", "{NICK}!user@host"));
+        assert!(matches("*", "nick!user@host"));
+        assert!(matches("*!*@*", "nick!user@host"));
+        assert!(matches("*!*@host", "nick!user@host"));
+        assert!(matches("*!*@*.example.com", "nick!user@a.b.example.com"));
+        assert!(matches("n?ck!*@*", "nick!user@host"));
+        assert!(matches("*!*user@host", "nick!~user@host"));
+        assert!(matches("**!*@h*t*", "nick!user@host"));
+        assert!(matches("", ""));
+
+        assert!(!matches("", "nick!user@host"));
+        assert!(!matches("nick!user@host", "nick!user@host2"));
+        assert!(!matches("nick!user@host2", "nick!user@host"));
+        assert!(!matches("*!*@*.example.com", "nick!user@example.com"));
+        assert!(!matches("n?ck!*@*", "nck!user@host"));
+        assert!(!matches("*!user@host", "nick!~user@host"));
+        assert!(!matches("nick", "nick!user@host"));
+    }
+
+    #[test]
+    fn test_source() {
+        let m: Message = ":nick!user@host PRIVMSG #c :hi".parse().unwrap();
+        let s = Source::new(&m).unwrap();
+        assert_eq!("nick", s.nick);
+        assert_eq!(Some("nick!user@host"), s.mask);
+        assert_eq!(None, s.account);
+        assert!(!s.is_bot);
+        assert!(s.matches_any(&["*!*@host".into()]));
+        assert!(!s.matches_any(&[]));
+        assert!(!s.account_in(&["nick".into()]));
+
+        let m: Message = "@account=Acct;bot :nick!user@host PRIVMSG #c :hi"
+            .parse().unwrap();
+        let s = Source::new(&m).unwrap();
+        assert_eq!(Some("Acct"), s.account);
+        assert!(s.is_bot);
+        assert!(s.account_in(&["acct".into()]));
+
+        let m: Message = "@account=*;draft/bot= :nick!user@host PRIVMSG #c :hi"
+            .parse().unwrap();
+        let s = Source::new(&m).unwrap();
+        assert_eq!(None, s.account);
+        assert!(s.is_bot);
+
+        let m: Message = ":irc.example.com NOTICE * :hi".parse().unwrap();
+        assert!(Source::new(&m).is_none());
+    }
+}
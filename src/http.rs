@@ -1,9 +1,10 @@
 use std::{
+    fmt,
     time::Duration,
     io::Read,
     thread,
 };
-use failure::Error;
+use failure::{Error, Fail};
 use reqwest::{
     header::{
         HeaderMap,
@@ -22,7 +23,8 @@ use failure::bail;
 
 use crate::{
     config::Rtd,
-    title::{parse_title, get_mime, get_image_metadata}
+    filter::{Action, UrlFilter},
+    title::{parse_title, parse_canonical, get_mime, get_image_metadata}
 };
 
 const CHUNK_BYTES: u64 = 100 * 1024; // 100kB
@@ -42,6 +44,7 @@ pub struct RetrieverBuilder<'a> {
     user_agent: Option<&'a str>,
     accept_lang: &'a str,
     redirect_limit: Option<usize>,
+    url_filters: Vec<&'a UrlFilter>,
 }
 
 impl<'a> RetrieverBuilder<'a> {
@@ -75,6 +78,13 @@ impl<'a> RetrieverBuilder<'a> {
         self
     }
 
+    /// Check the URL of each redirection against URL filters, and refuse to
+    /// follow it if any denies it
+    pub fn url_filters(mut self, url_filters: impl Iterator<Item = &'a UrlFilter>) -> Self {
+        self.url_filters.extend(url_filters);
+        self
+    }
+
     pub fn build(&self) -> Result<Retriever, Error> {
         let mut headers = HeaderMap::new();
 
@@ -95,9 +105,21 @@ impl<'a> RetrieverBuilder<'a> {
             builder = builder.timeout(timeout)
         };
 
-        if let Some(limit) = self.redirect_limit {
-            builder = builder.redirect(Policy::limited(limit))
-        };
+        let limit = self.redirect_limit;
+        let url_filters: Vec<UrlFilter> = self.url_filters.iter()
+            .map(|&f| f.clone())
+            .collect();
+
+        builder = builder.redirect(Policy::custom(move |attempt| {
+            if limit.is_some_and(|l| attempt.previous().len() >= l) {
+                attempt.error("too many redirects")
+            } else if url_filters.iter().any(|f| f.check(attempt.url()) == Action::Deny) {
+                let msg = format!("redirection to {} is not permitted", attempt.url());
+                attempt.error(msg)
+            } else {
+                attempt.follow()
+            }
+        }));
 
         let client = builder
             .build()?;
@@ -177,13 +199,132 @@ impl Retriever {
     }
 }
 
+/// Details of a page, for use in responses
+#[derive(Debug, Default, Clone)]
+pub struct PageInfo {
+    pub title: String,
+    /// URL of the page, after following any redirects
+    pub final_url: Option<String>,
+    /// content type, without parameters
+    pub mime: Option<String>,
+    /// human-readable content length, if known
+    pub size: Option<String>,
+    /// name of the plugin which provided the title, if any
+    pub plugin: Option<&'static str>,
+    /// the `<link rel="canonical">` of the page, if it has one
+    pub canonical: Option<String>,
+}
+
+/// The kind of failure which prevented a page from being looked up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureKind {
+    Timeout,
+    Connect,
+    /// the server responded with a 4xx status
+    ClientError,
+    /// the server responded with a 5xx status
+    ServerError,
+    /// the page was retrieved, but no title was found
+    NoTitle,
+    Other,
+}
+
+impl FailureKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            FailureKind::Timeout => "timeout",
+            FailureKind::Connect => "connect",
+            FailureKind::ClientError => "client-error",
+            FailureKind::ServerError => "server-error",
+            FailureKind::NoTitle => "no-title",
+            FailureKind::Other => "other",
+        }
+    }
+
+    /// whether the failure is likely to affect any page from the host, rather
+    /// than only the page requested
+    pub fn is_host_failure(self) -> bool {
+        matches!(self, FailureKind::Timeout | FailureKind::Connect | FailureKind::ServerError)
+    }
+}
+
+/// A failure to look up a page
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub kind: FailureKind,
+    /// HTTP status of the response, if there was one
+    pub status: Option<u16>,
+    pub message: String,
+    /// whether this is an earlier failure, from the cache
+    pub cached: bool,
+}
+
+impl Failure {
+    pub fn new(kind: FailureKind, message: &str) -> Self {
+        Self { kind, status: None, message: message.to_string(), cached: false }
+    }
+
+    /// Classify an error from looking up a page
+    pub fn from_error(err: &Error) -> Self {
+        let message = err.to_string();
+
+        if err.downcast_ref::<NoTitle>().is_some() {
+            return Self::new(FailureKind::NoTitle, &message);
+        }
+
+        let err = match err.downcast_ref::<reqwest::Error>() {
+            Some(e) => e,
+            None => return Self::new(FailureKind::Other, &message),
+        };
+
+        let status = err.status();
+        let kind = match status {
+            _ if err.is_timeout() => FailureKind::Timeout,
+            _ if err.is_connect() => FailureKind::Connect,
+            Some(s) if s.is_client_error() => FailureKind::ClientError,
+            Some(s) if s.is_server_error() => FailureKind::ServerError,
+            _ => FailureKind::Other,
+        };
+
+        Self { kind, status: status.map(|s| s.as_u16()), message, cached: false }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Fail for Failure {}
+
+/// A page was retrieved, but no title could be found in it
+#[derive(Debug)]
+pub struct NoTitle(String);
+
+impl fmt::Display for NoTitle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: failed to parse title", self.0)
+    }
+}
+
+impl Fail for NoTitle {}
+
 pub fn resolve_url(url: &str, rtd: &Rtd) -> Result<String, Error> {
+    resolve(url, rtd).map(|p| p.title)
+}
+
+pub fn resolve(url: &str, rtd: &Rtd) -> Result<PageInfo, Error> {
     let client = rtd.get_client()?;
     let mut resp = client.request(url)?;
-    get_title(&mut resp, rtd, false)
+    get_page_info(&mut resp, rtd, false)
 }
 
 pub fn get_title(resp: &mut Response, rtd: &Rtd, dump: bool) -> Result<String, Error> {
+    get_page_info(resp, rtd, dump).map(|p| p.title)
+}
+
+pub fn get_page_info(resp: &mut Response, rtd: &Rtd, dump: bool) -> Result<PageInfo, Error> {
     // get content type
     let content_type = resp.headers().get(CONTENT_TYPE)
         .and_then(|typ| typ.to_str().ok())
@@ -199,6 +340,10 @@ pub fn get_title(resp: &mut Response, rtd: &Rtd, dump: bool) -> Result<String, E
         trace!("[{}] {}", k, v.to_str().unwrap());
     });
 
+    // only HTML pages are searched for a canonical link
+    let is_html = content_type.as_ref()
+        .is_none_or(|m| (m.type_(), m.subtype()) == (TEXT, HTML));
+
     // vector to hold page content, which is progressively built from chunks of
     // downloaded data until a title is found (up to CHUNKS_MAX chunks)
     let mut body = Vec::new();
@@ -231,15 +376,26 @@ pub fn get_title(resp: &mut Response, rtd: &Rtd, dump: bool) -> Result<String, E
         };
 
         match title {
-            Some(t) => {
+            Some(title) => {
                 trace!("title found in {} chunks ({} B)", i, i * CHUNK_BYTES);
-                return Ok(t)
+                return Ok(PageInfo {
+                    title,
+                    final_url: Some(resp.url().to_string()),
+                    mime: content_type.map(|m| m.essence_str().to_string()),
+                    size: Some(size.replace(' ', "")).filter(|_| len > 0),
+                    plugin: None,
+                    canonical: Some(&contents)
+                        .filter(|_| is_html)
+                        .and_then(|c| parse_canonical(c))
+                        .and_then(|c| resp.url().join(&c).ok())
+                        .map(|c| c.to_string()),
+                });
             },
             None => continue,
         }
     }
 
-    bail!(format!("{}: failed to parse title", resp.url()));
+    Err(NoTitle(resp.url().to_string()).into())
 }
 
 /// HTTP tests
@@ -262,7 +418,6 @@ pub fn get_title(resp: &mut Response, rtd: &Rtd, dump: bool) -> Result<String, E
 ///
 /// In the future a better way to choose an inconspicuous, unused port for
 /// each test could be advantageous, too.
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -348,10 +503,15 @@ mod tests {
 
         for t in files_meta {
             let url = format!("http://127.0.0.1:28482/{}", t.0);
+            let info = resolve(&url, &rtd).unwrap();
+            assert_eq!(info.title, String::from(t.1));
+            assert_eq!(info.final_url, Some(url));
             assert_eq!(
-                resolve_url(&url, &rtd).unwrap(),
-                String::from(t.1)
-            )
+                info.mime.as_deref(),
+                get_ctype(Path::new(t.0)).split(';').next()
+            );
+            assert!(info.size.is_some());
+            assert!(info.plugin.is_none());
         }
 
         server_thread.join().unwrap();
@@ -492,6 +652,44 @@ mod tests {
         res
     }
 
+    #[test]
+    fn redirect_filtered() {
+        let bind = "127.0.0.1:28281";
+        let url = format!("http://{}/r_filter", bind);
+        let h_loc = Header::from_bytes("location", "/private/r_filter_r").unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http(bind).unwrap();
+            while let Ok(Some(rq)) = server.recv_timeout(Duration::from_millis(500)) {
+                tx.send(rq.url().to_string()).unwrap();
+                let resp = Response::from_string("<title>hello</title>")
+                    .with_status_code(301)
+                    .with_header(h_loc.clone());
+                thread::sleep(Duration::from_millis(10));
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        // wait for server thread to be ready
+        thread::sleep(Duration::from_millis(50));
+
+        let mut rtd = Rtd::new();
+        rtd.conf.url_filter = toml::de::from_str(r#"
+            [[rules]]
+            action = "deny"
+            path = "/private/"
+        "#).unwrap();
+        let rtd = rtd.init_http_client().unwrap();
+
+        // the redirection is denied, and not followed
+        let err = resolve_url(&url, &rtd).unwrap_err();
+        assert!(err.to_string().contains("not permitted"), "{}", err);
+
+        server_thread.join().unwrap();
+        assert_eq!(vec!["/r_filter".to_string()], rx.try_iter().collect::<Vec<_>>());
+    }
+
     #[test]
     fn redirect_absolute_location() {
         let bind = "127.0.0.1:28280";
@@ -716,4 +914,39 @@ mod tests {
         server_thread.join().unwrap();
         res
     }
+
+    #[test]
+    fn test_failure_kinds() {
+        let rtd = Rtd::new().init_http_client().unwrap();
+        let fail = |url: &str| Failure::from_error(&resolve(url, &rtd).unwrap_err());
+
+        // nothing is listening on the port, once the listener is dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap()
+            .local_addr().unwrap()
+            .port();
+        let f = fail(&format!("http://127.0.0.1:{}/", port));
+        assert_eq!((FailureKind::Connect, None), (f.kind, f.status));
+        assert!(f.kind.is_host_failure());
+
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("127.0.0.1:28489").unwrap();
+            for status in [404, 200].iter() {
+                let rq = server.recv().unwrap();
+                let resp = Response::from_string("no title here")
+                    .with_status_code(*status);
+                rq.respond(resp).unwrap();
+            }
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let f = fail("http://127.0.0.1:28489/missing");
+        assert_eq!((FailureKind::ClientError, Some(404)), (f.kind, f.status));
+        assert!(!f.kind.is_host_failure());
+
+        let f = fail("http://127.0.0.1:28489/page");
+        assert_eq!(FailureKind::NoTitle, f.kind);
+        assert_eq!("http://127.0.0.1:28489/page: failed to parse title", f.to_string());
+
+        server_thread.join().unwrap();
+    }
 }
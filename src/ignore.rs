@@ -0,0 +1,219 @@
+/*
+ * Ignoring messages from users
+ *
+ */
+use std::str::FromStr;
+use regex::Regex;
+use failure::{Error, bail};
+
+use crate::{
+    feat,
+    config::{Rtd, Parameters, ConfRegex, irc_lowercase},
+    hostmask::Source,
+};
+
+/// The ways in which a user may be ignored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// exact nickname
+    Nick,
+    /// `nick!user@host` glob pattern
+    Mask,
+    /// regular expression matched against the nickname
+    Regex,
+    /// services account
+    Account,
+}
+
+impl FromStr for Kind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "nick" => Kind::Nick,
+            "mask" => Kind::Mask,
+            "regex" => Kind::Regex,
+            "account" => Kind::Account,
+            _ => bail!("unknown ignore type `{}`", s),
+        })
+    }
+}
+
+impl Kind {
+    /// Guess the kind of an ignore given without one, i.e. a hostmask if it
+    /// looks like one, otherwise a nickname
+    pub fn guess(value: &str) -> Self {
+        if value.contains(['!', '@']) {
+            Kind::Mask
+        } else {
+            Kind::Nick
+        }
+    }
+}
+
+/// Check whether messages from a user should be ignored
+pub fn is_ignored(rtd: &Rtd, source: &Source) -> bool {
+    let params = &rtd.conf.params;
+    let nick = irc_lowercase(source.nick);
+
+    (feat!(rtd, ignore_bots) && source.is_bot)
+        || params.ignore_nicks.iter().any(|n| irc_lowercase(n) == nick)
+        || params.ignore_nick_regexes.iter().any(|r| r.0.is_match(source.nick))
+        || source.matches_any(&params.ignore_masks)
+        || source.account_in(&params.ignore_accounts)
+}
+
+/// A list of ignores of one kind in the configuration
+enum List<'a> {
+    Strings(&'a mut Vec<String>),
+    Regexes(&'a mut Vec<ConfRegex>),
+}
+
+fn list_mut(params: &mut Parameters, kind: Kind) -> List<'_> {
+    match kind {
+        Kind::Nick => List::Strings(&mut params.ignore_nicks),
+        Kind::Mask => List::Strings(&mut params.ignore_masks),
+        Kind::Account => List::Strings(&mut params.ignore_accounts),
+        Kind::Regex => List::Regexes(&mut params.ignore_nick_regexes),
+    }
+}
+
+/// Add an ignore to the configuration
+pub fn add(params: &mut Parameters, kind: Kind, value: &str) -> Result<(), Error> {
+    match list_mut(params, kind) {
+        List::Strings(list) => {
+            if list.iter().any(|v| v == value) {
+                bail!("already ignoring {}", value);
+            }
+            list.push(value.to_string());
+        },
+        List::Regexes(list) => {
+            if list.iter().any(|r| r.0.as_str() == value) {
+                bail!("already ignoring regex {}", value);
+            }
+            list.push(ConfRegex(Regex::new(value)?));
+        },
+    }
+
+    Ok(())
+}
+
+/// Remove an ignore from the configuration
+pub fn remove(params: &mut Parameters, kind: Kind, value: &str) -> Result<(), Error> {
+    let removed = match list_mut(params, kind) {
+        List::Strings(list) => list.iter().position(|v| v == value)
+            .map(|i| list.remove(i))
+            .is_some(),
+        List::Regexes(list) => list.iter().position(|r| r.0.as_str() == value)
+            .map(|i| list.remove(i))
+            .is_some(),
+    };
+
+    if !removed {
+        bail!("not ignoring {}", value);
+    }
+
+    Ok(())
+}
+
+/// Describe the configured ignores
+pub fn describe(params: &Parameters) -> String {
+    let regexes: Vec<_> = params.ignore_nick_regexes
+        .iter()
+        .map(|r| r.0.as_str())
+        .collect();
+
+    [
+        ("nicks", params.ignore_nicks.join(" ")),
+        ("masks", params.ignore_masks.join(" ")),
+        ("regexes", regexes.join(" ")),
+        ("accounts", params.ignore_accounts.join(" ")),
+    ]
+        .iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(k, v)| format!("{}: {}", k, v))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use irc::client::prelude::Message;
+
+    fn ignored(rtd: &Rtd, raw: &str) -> bool {
+        let message: Message = raw.parse().unwrap();
+        is_ignored(rtd, &Source::new(&message).unwrap())
+    }
+
+    #[test]
+    fn test_kind() {
+        assert_eq!(Kind::Nick, "nick".parse().unwrap());
+        assert_eq!(Kind::Account, "account".parse().unwrap());
+        assert!("nicks".parse::<Kind>().is_err());
+        assert_eq!(Kind::Nick, Kind::guess("bot"));
+        assert_eq!(Kind::Mask, Kind::guess("*!*@host"));
+        assert_eq!(Kind::Mask, Kind::guess("*@host"));
+    }
+
+    #[test]
+    fn test_is_ignored() {
+        let mut rtd = Rtd::default();
+        let plain = ":Bot1!~bot@bots.example.com PRIVMSG #c :hi";
+        let tagged = "@account=robot;bot :human!~h@example.org PRIVMSG #c :hi";
+
+        assert!(!ignored(&rtd, plain));
+        assert!(!ignored(&rtd, tagged));
+
+        rtd.conf.params.ignore_nicks = vec!["bot1".into()];
+        assert!(ignored(&rtd, plain));
+        rtd.conf.params.ignore_nicks.clear();
+
+        rtd.conf.params.ignore_masks = vec!["*!*@*.example.com".into()];
+        assert!(ignored(&rtd, plain));
+        assert!(!ignored(&rtd, tagged));
+        rtd.conf.params.ignore_masks.clear();
+
+        add(&mut rtd.conf.params, Kind::Regex, "^[Bb]ot\\d+$").unwrap();
+        assert!(ignored(&rtd, plain));
+        assert!(!ignored(&rtd, tagged));
+        rtd.conf.params.ignore_nick_regexes.clear();
+
+        rtd.conf.params.ignore_accounts = vec!["Robot".into()];
+        assert!(!ignored(&rtd, plain));
+        assert!(ignored(&rtd, tagged));
+        rtd.conf.params.ignore_accounts.clear();
+
+        assert!(!ignored(&rtd, tagged));
+        rtd.conf.features.ignore_bots = true;
+        assert!(ignored(&rtd, tagged));
+        assert!(!ignored(&rtd, plain));
+    }
+
+    #[test]
+    fn test_add_remove() {
+        let mut params = Parameters::default();
+
+        for (kind, value) in &[
+            (Kind::Nick, "bot"),
+            (Kind::Mask, "*!*@host"),
+            (Kind::Regex, "^bot"),
+            (Kind::Account, "robot"),
+        ] {
+            add(&mut params, *kind, value).unwrap();
+            assert!(add(&mut params, *kind, value).is_err());
+        }
+
+        assert!(add(&mut params, Kind::Regex, "(").is_err());
+        assert_eq!(
+            "nicks: bot; masks: *!*@host; regexes: ^bot; accounts: robot",
+            describe(&params)
+        );
+
+        remove(&mut params, Kind::Mask, "*!*@host").unwrap();
+        remove(&mut params, Kind::Regex, "^bot").unwrap();
+        assert!(remove(&mut params, Kind::Regex, "^bot").is_err());
+        assert!(remove(&mut params, Kind::Nick, "robot").is_err());
+        assert_eq!("nicks: bot; accounts: robot", describe(&params));
+    }
+}
@@ -0,0 +1,394 @@
+/*
+ * IRCv3 capabilities, message tags, and batches
+ *
+ */
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use failure::{Error, bail};
+use irc::client::prelude::*;
+use irc::proto::{CapSubCommand, message::Tag};
+use log::{debug, info};
+
+use crate::sasl::Sasl;
+
+/// Capabilities requested, if the server supports them
+const WANTED: [&str; 6] = [
+    "message-tags",
+    "server-time",
+    "account-tag",
+    "echo-message",
+    "batch",
+    "znc.in/batch",
+];
+
+/// Batch types containing messages played back from history, rather than
+/// sent live
+const PLAYBACK: [&str; 3] = ["CHATHISTORY", "DRAFT/CHATHISTORY", "ZNC.IN/PLAYBACK"];
+
+/// Register the connection, negotiating capabilities first
+///
+/// Unlike `identify()`, this doesn't end capability negotiation, which
+/// is done once the server has acknowledged the capabilities requested.
+pub fn register(client: &IrcClient) -> Result<(), Error> {
+    let config = client.config();
+
+    client.send(Command::CAP(None, CapSubCommand::LS, Some("302".into()), None))?;
+    if !config.password().is_empty() {
+        client.send(Command::PASS(config.password().to_owned()))?;
+    }
+    client.send(Command::NICK(config.nickname()?.to_owned()))?;
+    client.send(Command::USER(
+        config.username().to_owned(),
+        "0".to_owned(),
+        config.real_name().to_owned(),
+    ))?;
+
+    Ok(())
+}
+
+/// Capability negotiation state
+#[derive(Debug, Default, Clone)]
+pub struct Caps {
+    /// capabilities the server supports, with their values
+    available: HashMap<String, String>,
+    enabled: Vec<String>,
+    done: bool,
+}
+
+impl Caps {
+    /// Check whether a capability has been enabled
+    pub fn enabled(&self, cap: &str) -> bool {
+        self.enabled.iter().any(|c| c == cap)
+    }
+
+    /// End capability negotiation, if not already ended
+    pub fn end(&mut self) -> Vec<Command> {
+        if self.done {
+            return vec![];
+        }
+
+        self.done = true;
+        info!("capabilities enabled: {}", self.enabled.join(" "));
+        vec![Command::CAP(None, CapSubCommand::END, None, None)]
+    }
+
+    /// Handle a CAP message from the server, returning any commands to send
+    ///
+    /// If SASL authentication is configured, negotiation isn't ended until
+    /// authentication succeeds, and fails if the server doesn't support it.
+    pub fn handle(&mut self, command: &Command, sasl: &Sasl) -> Result<Vec<Command>, Error> {
+        let (sub, param, caps) = match command {
+            Command::CAP(_, sub, param, caps) => (sub, param.as_deref(), caps.as_deref()),
+            _ => return Ok(vec![]),
+        };
+
+        // a list of capabilities may be given without a colon
+        let (more, caps) = match (param, caps) {
+            (Some("*"), caps) => (true, caps.unwrap_or_default()),
+            (param, None) => (false, param.unwrap_or_default()),
+            (_, Some(caps)) => (false, caps),
+        };
+
+        let authenticate = sasl.enabled() && !self.done;
+
+        match sub {
+            CapSubCommand::LS => {
+                // capabilities may have values, e.g. `sasl=PLAIN,EXTERNAL`
+                self.available.extend(caps.split_whitespace().map(|c| {
+                    let (name, value) = c.split_once('=').unwrap_or((c, ""));
+                    (name.to_string(), value.to_string())
+                }));
+
+                if more || self.done {
+                    return Ok(vec![]);
+                }
+
+                let mut req: Vec<&str> = WANTED
+                    .iter()
+                    .filter(|w| self.available.contains_key(**w))
+                    .copied()
+                    .collect();
+
+                if authenticate {
+                    let mechanism = sasl.mechanism.name();
+                    match self.available.get("sasl") {
+                        None => bail!("the server doesn't support SASL authentication"),
+                        Some(mechs) if !mechs.is_empty()
+                            && !mechs.split(',').any(|m| m.eq_ignore_ascii_case(mechanism)) =>
+                        {
+                            bail!("the server doesn't support SASL {} authentication, only {}",
+                                mechanism, mechs);
+                        },
+                        Some(_) => req.push("sasl"),
+                    }
+                }
+
+                if req.is_empty() {
+                    return Ok(self.end());
+                }
+
+                debug!("requesting capabilities: {}", req.join(" "));
+                Ok(vec![Command::CAP(None, CapSubCommand::REQ, None, Some(req.join(" ")))])
+            },
+            CapSubCommand::ACK => {
+                for cap in caps.split_whitespace() {
+                    match cap.strip_prefix('-') {
+                        Some(cap) => self.enabled.retain(|c| c != cap),
+                        None => self.enabled.push(cap.to_string()),
+                    }
+                }
+
+                if authenticate && caps.split_whitespace().any(|c| c == "sasl") {
+                    return Ok(vec![sasl.start()]);
+                }
+
+                Ok(self.end())
+            },
+            CapSubCommand::NAK if authenticate => bail!("the server doesn't support SASL authentication"),
+            CapSubCommand::NAK => Ok(self.end()),
+            _ => Ok(vec![]),
+        }
+    }
+}
+
+/// Open batches, by reference tag, with their types
+#[derive(Debug, Default, Clone)]
+pub struct Batches {
+    open: HashMap<String, String>,
+}
+
+impl Batches {
+    /// Keep track of batches opened and closed by a BATCH message
+    pub fn handle(&mut self, message: &Message) {
+        let (reference, kind) = match &message.command {
+            Command::BATCH(r, kind, _) => (r, kind),
+            _ => return,
+        };
+
+        if let Some(r) = reference.strip_prefix('-') {
+            self.open.remove(r);
+        } else if let Some(r) = reference.strip_prefix('+') {
+            // batches nested in a playback batch are also playback
+            let kind = if self.is_playback(message) {
+                PLAYBACK[0].to_string()
+            } else {
+                kind.as_ref().map(|k| k.to_str().to_uppercase()).unwrap_or_default()
+            };
+            self.open.insert(r.to_string(), kind);
+        }
+    }
+
+    /// Check whether a message is part of a playback batch
+    pub fn is_playback(&self, message: &Message) -> bool {
+        Tags::new(message).batch
+            .and_then(|b| self.open.get(&b))
+            .is_some_and(|kind| PLAYBACK.contains(&kind.as_str()))
+    }
+}
+
+/// IRCv3 tags of a message
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Tags {
+    /// unique ID of the message
+    pub msgid: Option<String>,
+    /// time the message was sent, from `server-time`
+    pub time: Option<DateTime<Utc>>,
+    /// services account of the sender
+    pub account: Option<String>,
+    /// reference tag of the batch the message is part of
+    pub batch: Option<String>,
+}
+
+impl Tags {
+    pub fn new(message: &Message) -> Self {
+        let get = |name: &str| message.tags
+            .as_ref()
+            .and_then(|t| t.iter().find(|Tag(k, _)| k == name))
+            .and_then(|Tag(_, v)| v.as_deref())
+            .map(unescape)
+            .filter(|v| !v.is_empty());
+
+        Self {
+            msgid: get("msgid"),
+            time: get("time")
+                .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+                .map(|t| t.with_timezone(&Utc)),
+            account: get("account").filter(|a| a != "*"),
+            batch: get("batch"),
+        }
+    }
+}
+
+/// Unescape a tag value
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => {},
+        }
+    }
+
+    out
+}
+
+/// Escape a tag value
+pub fn escape(value: &str) -> String {
+    value.chars().fold(String::with_capacity(value.len()), |mut out, c| {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+        out
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sasl::Mechanism;
+
+    fn cap_sasl(caps: &mut Caps, sasl: &Sasl, raw: &str) -> Result<Vec<String>, Error> {
+        let message: Message = raw.parse().unwrap();
+        Ok(caps.handle(&message.command, sasl)?
+            .iter()
+            .map(|c| String::from(c).trim_end().to_string())
+            .collect())
+    }
+
+    fn cap(caps: &mut Caps, raw: &str) -> Vec<String> {
+        cap_sasl(caps, &Sasl::default(), raw).unwrap()
+    }
+
+    #[test]
+    fn test_caps() {
+        let mut caps = Caps::default();
+
+        assert!(cap(&mut caps, ":irc.test CAP * LS * :multi-prefix sasl=PLAIN batch").is_empty());
+        assert_eq!(vec!["CAP REQ :server-time echo-message batch"],
+            cap(&mut caps, ":irc.test CAP * LS :server-time echo-message"));
+        assert!(!caps.enabled("batch"));
+
+        assert_eq!(vec!["CAP END"],
+            cap(&mut caps, ":irc.test CAP * ACK :server-time batch echo-message"));
+        assert!(caps.enabled("batch"));
+        assert!(caps.enabled("server-time"));
+        assert!(!caps.enabled("sasl"));
+
+        // negotiation is only ended once
+        assert!(cap(&mut caps, ":irc.test CAP * ACK :-batch").is_empty());
+        assert!(!caps.enabled("batch"));
+    }
+
+    #[test]
+    fn test_caps_request() {
+        let mut caps = Caps::default();
+        assert_eq!(vec!["CAP REQ :message-tags account-tag"],
+            cap(&mut caps, ":irc.test CAP * LS :account-tag sasl message-tags"));
+        assert_eq!(vec!["CAP END"], cap(&mut caps, ":irc.test CAP * NAK :message-tags account-tag"));
+        assert!(!caps.enabled("message-tags"));
+
+        let mut caps = Caps::default();
+        assert_eq!(vec!["CAP END"], cap(&mut caps, ":irc.test CAP * LS :sasl"));
+
+        let mut caps = Caps::default();
+        assert_eq!(vec!["CAP REQ :batch"], cap(&mut caps, ":irc.test CAP * LS batch"));
+        assert_eq!(vec!["CAP END"], cap(&mut caps, ":irc.test CAP * ACK batch"));
+        assert!(caps.enabled("batch"));
+    }
+
+    #[test]
+    fn test_caps_sasl() {
+        let sasl = Sasl {
+            mechanism: Mechanism::Plain,
+            password: "pass".into(),
+            ..Sasl::default()
+        };
+        let mut caps = Caps::default();
+        assert_eq!(vec!["CAP REQ :batch sasl"],
+            cap_sasl(&mut caps, &sasl, ":irc.test CAP * LS :sasl=EXTERNAL,PLAIN batch").unwrap());
+        assert_eq!(vec!["AUTHENTICATE PLAIN"],
+            cap_sasl(&mut caps, &sasl, ":irc.test CAP * ACK :batch sasl").unwrap());
+        assert!(caps.enabled("sasl"));
+        assert_eq!(vec![Command::CAP(None, CapSubCommand::END, None, None)], caps.end());
+        assert!(caps.end().is_empty());
+
+        let mut caps = Caps::default();
+        assert_eq!(vec!["CAP REQ :sasl"], cap_sasl(&mut caps, &sasl, ":irc.test CAP * LS sasl").unwrap());
+        assert!(cap_sasl(&mut caps, &sasl, ":irc.test CAP * NAK sasl").is_err());
+
+        let mut caps = Caps::default();
+        assert!(cap_sasl(&mut caps, &sasl, ":irc.test CAP * LS :batch").is_err());
+
+        let mut caps = Caps::default();
+        assert!(cap_sasl(&mut caps, &sasl, ":irc.test CAP * LS :sasl=EXTERNAL").is_err());
+    }
+
+    #[test]
+    fn test_batches() {
+        let mut batches = Batches::default();
+        let live: Message = "@batch=a :n!u@h PRIVMSG #c :hi".parse().unwrap();
+        let nested: Message = "@batch=b :n!u@h PRIVMSG #c :hi".parse().unwrap();
+        let unbatched: Message = ":n!u@h PRIVMSG #c :hi".parse().unwrap();
+
+        for raw in &[
+            ":irc.test BATCH +a chathistory #c",
+            "@batch=a :irc.test BATCH +b netjoin",
+            ":irc.test BATCH +c netsplit irc.a irc.b",
+        ] {
+            batches.handle(&raw.parse().unwrap());
+        }
+
+        assert!(batches.is_playback(&live));
+        assert!(batches.is_playback(&nested));
+        assert!(!batches.is_playback(&unbatched));
+
+        batches.handle(&":irc.test BATCH -a".parse().unwrap());
+        assert!(!batches.is_playback(&live));
+        assert!(batches.is_playback(&nested));
+
+        batches.handle(&":irc.test BATCH +z znc.in/playback #c".parse().unwrap());
+        let m: Message = "@batch=z :n!u@h PRIVMSG #c :hi".parse().unwrap();
+        assert!(batches.is_playback(&m));
+    }
+
+    #[test]
+    fn test_tags() {
+        let m: Message = "@msgid=abc;time=2020-01-02T03:04:05.678Z;account=acct;batch=x \
+            :n!u@h PRIVMSG #c :hi".parse().unwrap();
+        let tags = Tags::new(&m);
+        assert_eq!(Some("abc".into()), tags.msgid);
+        assert_eq!("2020-01-02 03:04:05.678 UTC", tags.time.unwrap().to_string());
+        assert_eq!(Some("acct".into()), tags.account);
+        assert_eq!(Some("x".into()), tags.batch);
+
+        let m: Message = "@account=*;time=yesterday :n!u@h PRIVMSG #c :hi".parse().unwrap();
+        assert_eq!(Tags::default(), Tags::new(&m));
+
+        let m: Message = ":n!u@h PRIVMSG #c :hi".parse().unwrap();
+        assert_eq!(Tags::default(), Tags::new(&m));
+    }
+
+    #[test]
+    fn test_escape() {
+        for value in &["", "abc", "a;b c\\d\r\n", "\\"] {
+            assert_eq!(*value, unescape(&escape(value)));
+        }
+        assert_eq!("a\\:b\\sc\\\\", escape("a;b c\\"));
+        assert_eq!("ab", unescape("a\\b\\"));
+    }
+}
@@ -1,12 +1,41 @@
+// older tests predate these lints
+#![cfg_attr(test, allow(
+    clippy::bool_assert_comparison,
+    clippy::match_like_matches_macro,
+    clippy::manual_repeat_n,
+    clippy::needless_borrows_for_generic_args,
+    clippy::useless_vec,
+))]
+
 use lazy_static::lazy_static;
 
+pub mod history;
 pub mod sqlite;
+#[cfg(feature = "postgres")]
+pub mod postgres;
 pub mod http;
 pub mod title;
 pub mod config;
 pub mod message;
 pub mod tld;
+pub mod extract;
 pub mod plugins;
+pub mod commands;
+pub mod admin;
+pub mod hostmask;
+pub mod ratelimit;
+pub mod queue;
+pub mod filter;
+pub mod ignore;
+pub mod template;
+pub mod rewrite;
+pub mod wrap;
+pub mod ctcp;
+pub mod ircv3;
+pub mod sasl;
+pub mod worker;
+pub mod cache;
+pub mod canonical;
 pub mod buildinfo {
    include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
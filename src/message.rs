@@ -1,154 +1,628 @@
 use irc::client::prelude::*;
+use irc::proto::message::Tag;
 use std::{
     iter,
+    borrow::Cow,
     collections::HashSet,
+    sync::Arc,
+    time::Duration,
 };
 use unicode_segmentation::UnicodeSegmentation;
 use reqwest::Url;
 use regex::Regex;
-use log::{info, error, trace};
+use log::{info, warn, error, debug, trace};
 use lazy_static::lazy_static;
+use failure::{Error, bail};
+use chrono::{DateTime, Utc};
 
 use crate::{
     feat, param,
-    http::resolve_url,
-    sqlite::{Database, NewLogEntry},
-    config::Rtd,
-    tld::TLD,
+    http::{self, PageInfo, Failure},
+    history::{HistoryStore, NewLogEntry, NewErrorEntry, PrevPost, format_time},
+    config::{Rtd, irc_lowercase, is_channel},
+    extract::extract_urls,
+    title::url_similarity,
     plugins::TITLE_PLUGINS,
+    commands::{self, Context},
+    admin,
+    ratelimit::{Limits, Decision},
+    queue::SendQueue,
+    worker::Workers,
+    cache::{TitleCache, Lookup},
+    wrap,
+    filter::Action,
+    hostmask::Source,
+    ignore,
+    ctcp::Ctcp,
+    ircv3::{self, Tags},
+    template::time_ago,
 };
 
-pub fn handle_message(client: &IrcClient, message: &Message, rtd: &mut Rtd, db: &Database) {
+/// Handle a message from the server
+///
+/// An error is returned if the connection can't continue, i.e. if SASL
+/// authentication fails.
+pub fn handle_message(
+    queue: &SendQueue,
+    workers: &Workers,
+    message: &Message,
+    rtd: &mut Rtd,
+    db: &Arc<dyn HistoryStore>,
+) -> Result<(), Error> {
     trace!("{:?}", message.command);
 
     let sender = message.source_nickname();
     let target = message.response_target();
 
+    track_userhost(queue, rtd, message);
+
+    // messages played back from history, and echoes of our own messages,
+    // are never answered
+    if is_replay(queue, rtd, message) {
+        return Ok(());
+    }
+
     match &message.command {
-        Command::KICK(chan, nick, _) => kick(client, rtd, chan, nick),
-        Command::INVITE(nick, chan) => invite(client, rtd, nick, chan),
+        Command::CAP(..) => {
+            for reply in rtd.caps.handle(&message.command, &rtd.conf.sasl)? {
+                queue.send(reply);
+            }
+        },
+        Command::AUTHENTICATE(challenge) => {
+            let nick = queue.client().current_nickname();
+            for reply in rtd.conf.sasl.respond(nick, challenge)? {
+                queue.send(reply);
+            }
+        },
+        Command::Response(Response::RPL_SASLSUCCESS, ..) => {
+            info!("[{}] authenticated with SASL", rtd.conf.network.name);
+            for reply in rtd.caps.end() {
+                queue.send(reply);
+            }
+        },
+        Command::Response(
+            Response::ERR_NICKLOCKED | Response::ERR_SASLFAIL |
+            Response::ERR_SASLTOOLONG | Response::ERR_SASLABORT,
+            _,
+            text,
+        ) => {
+            bail!("SASL authentication failed: {}", text.as_deref().unwrap_or("unknown error"));
+        },
+        Command::BATCH(..) => rtd.batches.handle(message),
+        Command::KICK(chan, nick, _) => kick(queue, rtd, chan, nick),
+        Command::INVITE(nick, chan) => invite(queue, rtd, nick, chan),
         Command::PRIVMSG(tgt, msg) => {
             let sender = sender.unwrap();
+            let source = Source::new(message).unwrap_or_default();
+
+            // answer CTCP queries, and treat actions as any other message
+            let (msg, is_action) = match Ctcp::parse(msg) {
+                Some(ctcp) if ctcp.is_action() => (ctcp.params, true),
+                Some(ctcp) => {
+                    ctcp_reply(queue, rtd, &source, &ctcp);
+                    return Ok(());
+                },
+                None => (msg.as_str(), false),
+            };
+
+            // admin commands are only accepted by query
+            if !is_action && !is_channel(tgt) && admin::is_admin(&rtd.conf, &source) {
+                let text = strip_formatting(msg);
+                if let Some(result) = admin::dispatch(queue, rtd, db.as_ref(), &text) {
+                    admin_respond(queue, rtd, message, sender, &text, result);
+                    return Ok(());
+                }
+            }
+
             let target = target.unwrap_or(tgt);
-            let message = Msg::new(rtd, sender, target, msg);
-            privmsg(client, rtd, db, &message)
+            let rtd = rtd.for_channel(target);
+            let tags = Tags::new(message);
+            let mut message = Msg::new(&rtd, sender, target, msg);
+            message.is_ignored = ignore::is_ignored(&rtd, &source);
+            message.is_action = is_action;
+            message.tags = tags;
+            spawn_privmsg(queue, workers, rtd.into_owned(), db, message)
+        },
+        Command::NOTICE(tgt, msg) => {
+            // only notices sent to channels by users, which aren't CTCP
+            // replies, are looked at
+            let sender = match sender {
+                Some(s) if is_channel(tgt) && Ctcp::parse(msg).is_none() => s,
+                _ => return Ok(()),
+            };
+
+            let rtd = rtd.for_channel(tgt);
+            if !feat!(rtd, notice_titles) {
+                return Ok(());
+            }
+
+            let source = Source::new(message).unwrap_or_default();
+            let tags = Tags::new(message);
+            let mut message = Msg::new(&rtd, sender, tgt, msg);
+            message.is_ignored = ignore::is_ignored(&rtd, &source);
+            message.is_notice = true;
+            message.tags = tags;
+            spawn_privmsg(queue, workers, rtd.into_owned(), db, message)
         },
         _ => {},
     };
+
+    Ok(())
 }
 
-fn kick(client: &IrcClient, rtd: &mut Rtd, chan: &str, nick: &str) {
+/// keep track of our hostmask, which determines how much text fits in a
+/// line, from the welcome message, joins, and host changes
+fn track_userhost(queue: &SendQueue, rtd: &mut Rtd, message: &Message) {
+    let our_nick = queue.client().current_nickname();
+    let is_us = message.source_nickname() == Some(our_nick);
+
+    let userhost = match &message.command {
+        Command::Response(Response::RPL_WELCOME, _, Some(text)) => text
+            .rsplit(' ')
+            .next()
+            .and_then(|m| m.split_once('!'))
+            .map(|(_, userhost)| userhost.to_string()),
+        Command::JOIN(..) if is_us => message.prefix
+            .as_deref()
+            .and_then(|p| p.split_once('!'))
+            .map(|(_, userhost)| userhost.to_string()),
+        Command::CHGHOST(user, host) if is_us => Some(format!("{}@{}", user, host)),
+        Command::Response(Response::RPL_HOSTHIDDEN, args, _) if args.len() > 1 => {
+            let user = rtd.userhost
+                .as_deref()
+                .and_then(|u| u.split_once('@'))
+                .map_or("", |(user, _)| user);
+            Some(format!("{}@{}", user, args[1]))
+        },
+        _ => None,
+    };
+
+    if let Some(userhost) = userhost.filter(|u| u.contains('@')) {
+        if rtd.userhost.as_ref() != Some(&userhost) {
+            debug!("our user@host is {}", userhost);
+            rtd.userhost = Some(userhost);
+        }
+    }
+}
+
+/// check whether a message was played back from history, e.g. by a bouncer,
+/// or is an echo of a message we sent, with `echo-message`
+fn is_replay(queue: &SendQueue, rtd: &Rtd, message: &Message) -> bool {
+    if !matches!(message.command, Command::PRIVMSG(..) | Command::NOTICE(..)) {
+        return false;
+    }
+
+    if rtd.batches.is_playback(message) {
+        trace!("ignoring message played back from history");
+        return true;
+    }
+
+    let our_nick = irc_lowercase(queue.client().current_nickname());
+    message.source_nickname().is_some_and(|n| irc_lowercase(n) == our_nick)
+}
+
+/// answer a CTCP query, by notice
+fn ctcp_reply(queue: &SendQueue, rtd: &Rtd, source: &Source, query: &Ctcp) {
+    if ignore::is_ignored(rtd, source) {
+        return;
+    }
+
+    debug!("[{}] CTCP {} from {}", rtd.conf.network.name, query.command, source.nick);
+
+    if let Some(reply) = rtd.conf.ctcp.reply(query) {
+        queue.send_notice(source.nick, reply);
+    }
+}
+
+fn kick(queue: &SendQueue, rtd: &mut Rtd, chan: &str, nick: &str) {
     if !feat!(rtd, autosave) {
         return;
     }
 
-    if nick != client.current_nickname() {
+    if nick != queue.client().current_nickname() {
         return;
     }
 
     info!("kicked from {}", chan);
 
     rtd.conf.remove_channel(chan);
+    autosave(rtd);
 }
 
-fn invite(client: &IrcClient, rtd: &mut Rtd, nick: &str, chan: &str) {
+fn invite(queue: &SendQueue, rtd: &mut Rtd, nick: &str, chan: &str) {
     if !feat!(rtd, invite) {
         return;
     }
 
-    if nick != client.current_nickname() {
+    if nick != queue.client().current_nickname() {
         return;
     }
 
     info!("invited to channel: {}", chan);
 
-    if let Err(e) = client.send_join(chan) {
-        error!("error joining channel: {}", e);
-    } else {
-        info!("joined {}", chan);
+    queue.send_join(chan);
 
-        if feat!(rtd, autosave) {
-            rtd.conf.add_channel(chan.to_string());
-        };
+    if feat!(rtd, autosave) {
+        rtd.conf.add_channel(chan.to_string());
+        autosave(rtd);
     };
 }
 
+/// write out the active configuration, if configured to do so
+pub(crate) fn autosave(rtd: &Rtd) {
+    if !feat!(rtd, autosave) {
+        return;
+    }
+
+    if let Err(e) = rtd.conf.save() {
+        error!("error saving configuration: {}", e);
+    }
+}
+
+/// reply to an admin command, and audit it to the status channels
+fn admin_respond(
+    queue: &SendQueue,
+    rtd: &Rtd,
+    message: &Message,
+    sender: &str,
+    text: &str,
+    result: Result<String, failure::Error>,
+) {
+    let reply = match result {
+        Ok(r) => r,
+        Err(e) => format!("error: {}", e),
+    };
+
+    send_fit(queue, rtd, "PRIVMSG", sender, &reply);
+
+    let source = message.prefix.as_deref().unwrap_or(sender);
+    let audit = format!("admin {}: {} → {}", source, text, reply);
+    info!("[{}] {}", rtd.conf.network.name, audit);
+    msg_status_chans(queue, rtd, audit);
+}
+
 #[derive(Debug, PartialEq)]
 enum TitleResp {
     Title(String),
     Error(String),
+    Throttled(String),
+    Filtered(String),
 }
 
 #[derive(Debug)]
-struct Msg<'a> {
+struct Msg {
     is_chanmsg: bool,
     is_ping: bool,
-    target: &'a str,
-    sender: &'a str,
-    text: &'a str,
+    /// whether the sender is configured to be ignored
+    is_ignored: bool,
+    /// whether the message is a CTCP ACTION, i.e. `/me`
+    is_action: bool,
+    /// whether the message was sent as a notice, which must never be
+    /// answered with a notice
+    is_notice: bool,
+    /// IRCv3 tags of the message
+    tags: Tags,
+    target: String,
+    sender: String,
+    /// message text, with any formatting codes removed
+    text: String,
+    /// message text, as received
+    raw: String,
 }
 
-impl<'a> Msg<'a> {
-    fn new(rtd: &Rtd, sender: &'a str, target: &'a str, text: &'a str) -> Msg<'a> {
+impl Msg {
+    fn new(rtd: &Rtd, sender: &str, target: &str, raw: &str) -> Msg {
         let our_nick = rtd.conf.client.nickname.as_ref().unwrap();
+        let text = strip_formatting(raw);
 
         Msg {
-            is_chanmsg: target.starts_with('#'),
-            is_ping: is_ping(our_nick, text),
-            sender,
-            target,
-            text,
+            is_chanmsg: is_channel(target),
+            is_ping: is_ping(our_nick, &text),
+            is_ignored: ignore::is_ignored(rtd, &Source { nick: sender, ..Source::default() }),
+            is_action: false,
+            is_notice: false,
+            tags: Tags::default(),
+            sender: sender.to_string(),
+            target: target.to_string(),
+            text: text.into_owned(),
+            raw: raw.to_string(),
         }
     }
 }
 
-fn privmsg(client: &IrcClient, rtd: &Rtd, db: &Database, msg: &Msg) {
+/// handle a message on a worker thread, so that looking up titles doesn't
+/// hold up the connection, sending responses in the order messages arrived
+/// in each channel
+fn spawn_privmsg(queue: &SendQueue, workers: &Workers, rtd: Rtd, db: &Arc<dyn HistoryStore>, msg: Msg) {
+    let queue = queue.ordered(&msg.target);
+    let db = db.clone();
+    let net = rtd.conf.network.name.clone();
+    let sender = msg.sender.clone();
+
+    if !workers.submit(move || privmsg(&queue, &rtd, db.as_ref(), &msg)) {
+        warn!("[{}] too many messages waiting, ignoring message from {}", net, sender);
+    }
+}
+
+fn privmsg(queue: &SendQueue, rtd: &Rtd, db: &dyn HistoryStore, msg: &Msg) {
+    debug!("[{}] <{}:{}> {:?}", rtd.conf.network.name, msg.sender, msg.target, msg.raw);
+
     // ignore messages sent to status channels
-    if param!(rtd, status_channels).contains(&msg.target.to_string()) {
-        if msg.is_ping || contains_urls(msg.text) {
+    if is_status_channel(rtd, &msg.target) {
+        if !msg.is_notice && (msg.is_ping || !extract_urls(&msg.text, false).is_empty()) {
             let m = format!("ignoring messages in channel {}", msg.target);
-            client.send_privmsg(&msg.sender, m).unwrap();
+            send_fit(queue, rtd, "PRIVMSG", &msg.sender, &m);
         }
         return;
     }
 
+    // messages invoking a command are not otherwise processed
+    if !msg.is_ignored && !msg.is_action && !msg.is_notice {
+        let ctx = Context {
+            rtd,
+            db,
+            sender: &msg.sender,
+            target: &msg.target,
+            is_chanmsg: msg.is_chanmsg,
+        };
+
+        if let Some(result) = commands::dispatch(&ctx, &msg.text) {
+            match result {
+                Ok(lines) => lines
+                    .iter()
+                    .for_each(|l| respond(queue, rtd, msg, l)),
+                Err(e) => respond(queue, rtd, msg, e),
+            }
+            return;
+        }
+    }
+
     let titles: Vec<_> = process_titles(rtd, db, msg).collect();
 
     for resp in &titles {
         match resp {
-            TitleResp::Title(t) => respond(client, rtd, msg, t),
-            TitleResp::Error(e) => respond_error(client, rtd, msg, e),
+            TitleResp::Title(t) => respond(queue, rtd, msg, t),
+            TitleResp::Error(e) => respond_error(queue, rtd, msg, e),
+            TitleResp::Throttled(t) if !msg.is_notice => {
+                send_fit(queue, rtd, "NOTICE", &msg.sender, t)
+            },
+            TitleResp::Throttled(_) => {},
+            TitleResp::Filtered(m) => respond(queue, rtd, msg, m),
         }
     }
 
     // if we had no url message and got a ping send nick response
-    if titles.is_empty() && msg.is_ping {
-        respond(client, rtd, msg, &param!(rtd, nick_response_str));
+    if titles.is_empty() && msg.is_ping && !msg.is_notice {
+        respond(queue, rtd, msg, &param!(rtd, nick_response_str));
     }
 
 }
 
 /// Run available plugins on a single URL, return the first successful title.
-fn process_plugins(rtd: &Rtd, url: &Url) -> Option<String> {
-    let result: String = TITLE_PLUGINS
+fn process_plugins(rtd: &Rtd, url: &Url) -> Option<PageInfo> {
+    TITLE_PLUGINS
         .iter()
         .filter(|p| p.check(&rtd.conf.plugins, url))
-        .filter_map(|p| p.evaluate(rtd, url).ok())
-        .take(1)
+        .filter_map(|p| p.evaluate(rtd, url).ok().map(|t| (p.name(), t)))
+        .find(|(_, title)| !title.is_empty())
+        .map(|(name, title)| PageInfo {
+            title,
+            plugin: Some(name),
+            ..PageInfo::default()
+        })
+}
+
+/// look up a URL, from a plugin if one applies, otherwise from the page
+/// itself, or from the cache, if it was looked up recently
+fn lookup(rtd: &Rtd, url: &Url) -> Lookup {
+    let http = &rtd.conf.http_params;
+    let ttl = Duration::from_secs(http.cache_ttl_s);
+    let error_ttl = Duration::from_secs(http.cache_error_ttl_s);
+
+    // results are only cached if they can be told apart from those of
+    // configurations with different settings
+    let key = match rtd.conf.lookup_fingerprint() {
+        Ok(fingerprint) => Some(TitleCache::key(url, fingerprint)),
+        Err(err) => {
+            error!("[{}] not caching titles: {}", rtd.conf.network.name, err);
+            None
+        },
+    };
+
+    if let Some(lookup) = key.as_ref().and_then(|k| rtd.cache.get(k, ttl, error_ttl)) {
+        debug!("[{}] CACHE HIT <{}> ({})", rtd.conf.network.name, url, rtd.cache.stats());
+        return lookup.map_err(|f| Failure { cached: true, ..f });
+    }
+
+    let lookup = match process_plugins(rtd, url) {
+        Some(page) => Ok(page),
+        None => http::resolve(url.as_str(), rtd).map_err(|e| Failure::from_error(&e)),
+    };
+
+    if let Some(key) = key.filter(|_| rtd.cache.enabled()) {
+        debug!("[{}] CACHE MISS <{}> ({})", rtd.conf.network.name, url, rtd.cache.stats());
+        rtd.cache.insert(key, lookup.clone());
+    }
+
+    lookup
+}
+
+/// get the title of a URL, and apply any title rewriting rules
+pub(crate) fn get_title(rtd: &Rtd, url: &Url) -> Result<PageInfo, Failure> {
+    let mut page = lookup(rtd, url)?;
+
+    let final_url = page.final_url.as_deref().and_then(|u| Url::parse(u).ok());
+    let hosts: Vec<&str> = [Some(url), final_url.as_ref()]
+        .iter()
+        .flatten()
+        .filter_map(|u| u.host_str())
         .collect();
 
-    if result.is_empty() {
-        None
+    page.title = rtd.conf.title_rewrite.apply(&page.title, &hosts);
+
+    Ok(page)
+}
+
+/// render a title response using the configured templates
+pub(crate) fn render_title(
+    rtd: &Rtd,
+    url: &Url,
+    page: &PageInfo,
+    user: &str,
+    channel: &str,
+    previous: Option<&PrevPost>,
+) -> String {
+    let templates = &rtd.conf.templates;
+    let template = match (previous, page.plugin) {
+        (Some(_), _) => &templates.repost,
+        (None, Some(_)) => &templates.plugin,
+        (None, None) => &templates.title,
+    };
+
+    let mask = |nick: &str| if feat!(rtd, mask_highlights) {
+        create_non_highlighting_name(nick)
     } else {
-        Some(result)
+        nick.to_string()
+    };
+
+    let user = mask(user);
+    let prev_user = previous.map(|p| mask(&p.user)).unwrap_or_default();
+    let last_user = previous.map(|p| mask(&p.last_user)).unwrap_or_default();
+    let count = previous.map(|p| p.count.to_string()).unwrap_or_default();
+
+    let now = Utc::now();
+    let time = |time: Option<DateTime<Utc>>| time.map(format_time).unwrap_or_default();
+    let ago = |time: Option<DateTime<Utc>>| time.map(|t| time_ago(t, now)).unwrap_or_default();
+    let prev_time = previous.map(PrevPost::time);
+    let last_time = previous.map(PrevPost::last_time);
+    let (prev_ago, last_ago) = (ago(prev_time), ago(last_time));
+    let (prev_time, last_time) = (time(prev_time), time(last_time));
+
+    // where a post was made, with its network if it was another
+    let net = &rtd.conf.network.name;
+    let location = |channel: &str, network: &Option<String>| match network {
+        Some(n) if n != net => format!("{} on {}", channel, n),
+        _ => channel.to_string(),
+    };
+    let prev_network = previous.and_then(|p| p.network.as_deref()).unwrap_or(net);
+    let last_network = previous.and_then(|p| p.last_network.as_deref()).unwrap_or(net);
+    let prev_location = previous.map(|p| location(&p.channel, &p.network)).unwrap_or_default();
+    let last_location = previous
+        .map(|p| location(&p.last_channel, &p.last_network))
+        .unwrap_or_default();
+
+    template.render(&[
+        ("title", &page.title),
+        ("url", url.as_str()),
+        ("domain", url.host_str().unwrap_or_default()),
+        ("final_url", page.final_url.as_deref().unwrap_or(url.as_str())),
+        ("mime", page.mime.as_deref().unwrap_or_default()),
+        ("size", page.size.as_deref().unwrap_or_default()),
+        ("user", &user),
+        ("channel", channel),
+        ("plugin", page.plugin.unwrap_or_default()),
+        ("count", &count),
+        ("prev_user", &prev_user),
+        ("prev_time", &prev_time),
+        ("prev_ago", &prev_ago),
+        ("prev_channel", previous.map_or("", |p| &p.channel)),
+        ("prev_network", previous.map_or("", |_| prev_network)),
+        ("prev_location", &prev_location),
+        ("last_user", &last_user),
+        ("last_time", &last_time),
+        ("last_ago", &last_ago),
+        ("last_channel", previous.map_or("", |p| &p.last_channel)),
+        ("last_network", previous.map_or("", |_| last_network)),
+        ("last_location", &last_location),
+    ])
+}
+
+/// render an error response using the configured template
+fn render_error(rtd: &Rtd, url: &Url, error: &str, user: &str, channel: &str) -> String {
+    rtd.conf.templates.error.render(&[
+        ("error", error),
+        ("url", url.as_str()),
+        ("domain", url.host_str().unwrap_or_default()),
+        ("user", user),
+        ("channel", channel),
+    ])
+}
+
+/// check whether a URL, or its host, has failed to be looked up too often
+/// recently
+pub(crate) fn is_failing(rtd: &Rtd, db: &dyn HistoryStore, url: &Url) -> bool {
+    let http = &rtd.conf.http_params;
+
+    if http.failure_limit == 0 {
+        return false;
+    }
+
+    let since = Utc::now() - chrono::Duration::seconds(http.failure_window_s as i64);
+    let host = url.host_str().unwrap_or_default();
+
+    match db.failure_counts(url.as_str(), host, since) {
+        Ok(c) => c.url >= http.failure_limit || c.host >= http.failure_limit,
+        Err(err) => {
+            error!("SQL error: {}", err);
+            false
+        },
+    }
+}
+
+/// check a title lookup against the rate limits, giving the notice to send if
+/// the sender or channel is throttled, and whether they should be sent it
+pub(crate) fn throttle(
+    rtd: &Rtd,
+    limits: &Limits,
+    sender: &str,
+    target: &str,
+) -> Option<(bool, String)> {
+    let throttled = match rtd.limiter.check(limits, sender, target) {
+        Decision::Allow => None,
+        Decision::UserThrottled { notify } => Some((notify, format!(
+            "Sorry {}, you're posting links too quickly, so I'll skip \
+            titles for a little while.", sender))),
+        Decision::ChannelThrottled { notify } => Some((notify, format!(
+            "Sorry, links are being posted in {} too quickly, so I'll \
+            skip titles for a little while.", target))),
+    };
+
+    if throttled.is_some() {
+        info!("[{}] THROTTLED <{}:{}>", rtd.conf.network.name, sender, target);
+    }
+
+    throttled
+}
+
+/// record a failure to look up a URL, posted in a channel
+pub(crate) fn log_failure(
+    rtd: &Rtd,
+    db: &dyn HistoryStore,
+    channel: &str,
+    url: &Url,
+    failure: &Failure,
+) {
+    let entry = NewErrorEntry {
+        url: url.as_str(),
+        error_info: &failure.message,
+        host: url.host_str().unwrap_or_default(),
+        kind: failure.kind.name(),
+        status: failure.status,
+        host_failure: failure.kind.is_host_failure(),
+        network: &rtd.conf.network.name,
+        channel,
+    };
+
+    if let Err(err) = db.add_error(&entry) {
+        error!("SQL error: {}", err);
     }
 }
 
 /// find titles in a message and generate responses
-fn process_titles(rtd: &Rtd, db: &Database, msg: &Msg) -> impl Iterator<Item = TitleResp> {
-    // return an empty iterator for messages originating from a nick
+fn process_titles(rtd: &Rtd, db: &dyn HistoryStore, msg: &Msg) -> impl Iterator<Item = TitleResp> {
+    // return an empty iterator for messages originating from a user
     // that is configured to be ignored
-    if param!(rtd, ignore_nicks).contains(&msg.sender.to_string()) {
+    if msg.is_ignored {
         return vec![].into_iter();
     }
 
@@ -156,126 +630,137 @@ fn process_titles(rtd: &Rtd, db: &Database, msg: &Msg) -> impl Iterator<Item = T
 
     let mut num_processed = 0;
     let mut dedup_urls = HashSet::new();
+    let limits = Limits::from_params(&rtd.conf.params);
 
-    // look at each space-separated message token
-    for token in msg.text.split_whitespace() {
-        // the token must not contain unsafe characters
-        if contains_unsafe_chars(token) {
+    // look at each URL found in the message
+    for url in extract_urls(&msg.text, feat!(rtd, partial_urls)) {
+        // the scheme must be http or https
+        if !["http", "https"].contains(&url.scheme()) {
             continue;
         }
 
-        // get a full URL for tokens without a scheme
-        let maybe_token = if feat!(rtd, partial_urls) {
-            add_scheme_for_tld(token)
-        } else {
-            None
-        };
+        // skip duplicate urls within the message
+        let mut key = rtd.conf.canonical.key(&url);
+        if dedup_urls.contains(&key) {
+            continue;
+        }
 
-        let token = maybe_token
-            .as_ref()
-            .map_or(token, String::as_str);
+        let token = url.as_str();
 
-        // the token must be a valid url
-        let url = match token.parse::<Url>() {
-            Ok(url) => url,
-            _ => continue,
-        };
-
-        // the scheme must be http or https
-        if !["http", "https"].contains(&url.scheme()) {
+        // skip URLs which are not permitted to be looked up
+        if rtd.conf.check_url(&url) == Action::Deny {
+            info!("[{}] FILTERED <{}>", rtd.conf.network.name, token);
+            let message = rtd.conf.url_filter_message();
+            if !message.is_empty() {
+                responses.push(TitleResp::Filtered(message.to_string()));
+            }
+            dedup_urls.insert(key);
             continue;
         }
 
-        // skip duplicate urls within the message
-        if dedup_urls.contains(&url) {
+        // skip URLs which, or whose hosts, have been failing
+        if is_failing(rtd, db, &url) {
+            info!("[{}] FAILING <{}>", rtd.conf.network.name, token);
+            dedup_urls.insert(key);
             continue;
         }
 
+        // stop looking up titles if the sender or channel is posting too
+        // quickly, letting them know once
+        if let Some((notify, notice)) = throttle(rtd, &limits, &msg.sender, &msg.target) {
+            if notify {
+                responses.push(TitleResp::Throttled(notice));
+            }
+            break;
+        }
+
         info!("[{}] RESOLVE <{}>", rtd.conf.network.name, token);
 
         // try to get the title from the url
-        let title = if let Some(title) = process_plugins(rtd, &url) {
-            title
-        } else {
-            match resolve_url(token, rtd) {
-                Ok(title) => title,
-                Err(err) => {
-                    error!("{:?}", err);
-                    responses.push(TitleResp::Error(err.to_string()));
-                    continue;
-                },
-            }
+        let page = match get_title(rtd, &url) {
+            Ok(page) => page,
+            Err(err) => {
+                error!("{:?}", err);
+                if !err.cached {
+                    log_failure(rtd, db, &msg.target, &url, &err);
+                }
+                let error = render_error(rtd, &url, &err.to_string(), &msg.sender, &msg.target);
+                responses.push(TitleResp::Error(error));
+                continue;
+            },
         };
 
+        // the page may say it's the same as another, under a different URL
+        if let Some(link_key) = rtd.conf.canonical
+            .link_key(&url, page.canonical.as_deref())
+            .filter(|k| *k != key)
+        {
+            dedup_urls.insert(key);
+            key = link_key;
+            if dedup_urls.contains(&key) {
+                continue;
+            }
+        }
+
         // create a log entry struct
         let entry = NewLogEntry {
-            title: &title,
+            title: &page.title,
             url: token,
-            user: msg.sender,
-            channel: msg.target,
+            url_key: &key,
+            user: &msg.sender,
+            channel: &msg.target,
+            network: &rtd.conf.network.name,
         };
 
-        // check for pre-post
+        // check for pre-post, limited to the same channel, to the same
+        // network unless the history is shared, and to recent posts, if
+        // required by configuration, adding a log entry to the database at
+        // the same time, if posted in a channel
         let pre_post = if feat!(rtd, history) {
-            db.check_prepost(token)
+            let cross_channel = feat!(rtd, cross_channel_history);
+            let channel = Some(msg.target.as_str()).filter(|_| !cross_channel);
+            let network = Some(rtd.conf.network.name.as_str())
+                .filter(|_| !(cross_channel && rtd.conf.database.shared));
+            let window = rtd.conf.database.prepost_window_days;
+            let since = Some(Utc::now() - chrono::Duration::days(window.into()))
+                .filter(|_| window > 0);
+            if msg.is_chanmsg {
+                db.check_and_add_log(&entry, network, channel, since)
+            } else {
+                db.check_prepost(&key, token, network, channel, since)
+            }
         } else {
             Ok(None)
         };
 
-        let pre_post_found = matches!(pre_post, Ok(Some(_)));
-
-        // limit pre-post to same channel if required by configuration
-        let pre_post = if feat!(rtd, cross_channel_history) {
-            pre_post
-        } else {
-            pre_post
-                .map(|p| p.and_then(|p| {
-                    if p.channel == msg.target {
-                        Some(p)
-                    } else {
-                        None
-                    }
-                }))
-        };
-
-        // generate response string
-        let mut msg = match pre_post {
-            Ok(Some(previous_post)) => {
-                let user = if feat!(rtd, mask_highlights) {
-                    create_non_highlighting_name(&previous_post.user)
-                } else {
-                    previous_post.user
-                };
-                format!("⤷ {} → {} {} ({})",
-                    title,
-                    previous_post.time_created,
-                    user,
-                    previous_post.channel
-                )
-            },
-            Ok(None) => {
-                // add new log entry to database, if posted in a channel
-                if feat!(rtd, history) && !pre_post_found && msg.is_chanmsg {
-                    if let Err(err) = db.add_log(&entry) {
-                        error!("SQL error: {}", err);
-                    }
-                }
-                format!("⤷ {}", title)
-            },
+        let previous = match pre_post {
+            Ok(previous) => previous,
             Err(err) => {
                 error!("SQL error: {}", err);
                 continue
             },
         };
 
-        // limit response length, see RFC1459
-        msg = utf8_truncate(&msg, 510);
+        // skip titles which only repeat the URL, which are still logged
+        let threshold = param!(rtd, title_url_similarity);
+        if threshold > 0.0 && page.plugin.is_none() {
+            let similarity = url_similarity(&url, &page.title);
+            if similarity >= threshold {
+                info!("[{}] SUPPRESSED <{}> {:?} ({:.2})",
+                    rtd.conf.network.name, token, page.title, similarity);
+                dedup_urls.insert(key);
+                continue;
+            }
+        }
+
+        // generate response string
+        let msg = render_title(rtd, &url, &page, &msg.sender, &msg.target, previous.as_ref());
 
         info!("[{}] {}", rtd.conf.network.name, msg);
 
         responses.push(TitleResp::Title(msg.to_string()));
 
-        dedup_urls.insert(url);
+        dedup_urls.insert(key);
 
         // limit the number of processed URLs
         num_processed += 1;
@@ -287,62 +772,99 @@ fn process_titles(rtd: &Rtd, db: &Database, msg: &Msg) -> impl Iterator<Item = T
     responses.into_iter()
 }
 
-/// send IRC response
-fn respond<S>(client: &IrcClient, rtd: &Rtd, msg: &Msg, text: S)
+/// send IRC response, split into as many lines as configured, to fit
+fn respond<S>(queue: &SendQueue, rtd: &Rtd, msg: &Msg, text: S)
 where
     S: ToString + std::fmt::Display,
 {
-    let result = if feat!(rtd, send_notice) && msg.is_chanmsg {
-        client.send_notice(&msg.target, &text)
-    } else {
-        client.send_privmsg(&msg.target, &text)
-    };
+    let notice = feat!(rtd, send_notice) && msg.is_chanmsg && !msg.is_notice;
+    let command = if notice { "NOTICE" } else { "PRIVMSG" };
+
+    for line in fit(queue, rtd, command, &msg.target, &text.to_string()) {
+        let command = if notice {
+            Command::NOTICE(msg.target.to_string(), line)
+        } else {
+            Command::PRIVMSG(msg.target.to_string(), line)
+        };
+        queue.send_message(reply_to(rtd, msg, command));
+    }
+}
+
+/// thread a response to the message it answers, with the `+draft/reply`
+/// client tag, if the server supports tags
+fn reply_to(rtd: &Rtd, msg: &Msg, command: Command) -> Message {
+    let mut message = Message::from(command);
+
+    if let Some(msgid) = msg.tags.msgid.as_deref().filter(|_| rtd.caps.enabled("message-tags")) {
+        let tag = Tag("+draft/reply".to_string(), Some(ircv3::escape(msgid)));
+        message.tags = Some(vec![tag]);
+    }
 
-    result.unwrap_or_else(|err| {
-        error!("Error sending response {}: {}", msg.target, err);
-    });
+    message
 }
 
-fn respond_error<S>(client: &IrcClient, rtd: &Rtd, msg: &Msg, text: S)
+/// split text into lines which fit in messages sent to a target
+fn fit(queue: &SendQueue, rtd: &Rtd, command: &str, target: &str, text: &str) -> Vec<String> {
+    let nick = queue.client().current_nickname();
+    let max = wrap::payload_len(nick, rtd.userhost.as_deref(), command, target);
+
+    wrap::split(
+        text,
+        max,
+        param!(rtd, continuation_lines).into(),
+        &param!(rtd, ellipsis),
+    )
+}
+
+/// send a message or notice to a target, split into as many lines as
+/// configured, to fit
+fn send_fit(queue: &SendQueue, rtd: &Rtd, command: &str, target: &str, text: &str) {
+    for line in fit(queue, rtd, command, target, text) {
+        match command {
+            "NOTICE" => queue.send_notice(target, line),
+            _ => queue.send_privmsg(target, line),
+        }
+    }
+}
+
+fn respond_error<S>(queue: &SendQueue, rtd: &Rtd, msg: &Msg, text: S)
 where
     S: ToString + std::fmt::Display,
 {
     // reply with error, if message was sent in a channel
     // always reply with errors in queries
     if !msg.is_chanmsg || feat!(rtd, reply_with_errors) {
-        respond(client, rtd, msg, &text);
+        respond(queue, rtd, msg, &text);
     };
 
     // send errors to poster by query
     // do not send if link was already sent in a query, since this
     // duplicates messages
     if msg.is_chanmsg && feat!(rtd, send_errors_to_poster) {
-        client.send_privmsg(&msg.sender, &text).unwrap();
+        send_fit(queue, rtd, "PRIVMSG", &msg.sender, &text.to_string());
     };
 
     // send error messages to status channels, for channel messages only
     // this may still leak link information from, e.g. secret channels
     if msg.is_chanmsg {
-        msg_status_chans(client, rtd, &text);
+        msg_status_chans(queue, rtd, &text);
     }
 }
 
-fn contains_urls(text: &str) -> bool {
-    text
-        .split_whitespace()
-        .filter(|token| token.parse::<Url>().is_ok())
-        .count() > 0
+lazy_static! {
+    /// mIRC style formatting codes: colour, with optional foreground and
+    /// background (`\x03NN,MM`), hex colour (`\x04RRGGBB,RRGGBB`), bold,
+    /// reset, monospace, reverse, italics, strikethrough, and underline
+    static ref FORMATTING: Regex = Regex::new(concat!(
+        r"\x03(\d{1,2}(,\d{1,2})?)?",
+        r"|\x04([[:xdigit:]]{6}(,[[:xdigit:]]{6})?)?",
+        r"|[\x02\x0F\x11\x16\x1D\x1E\x1F]",
+    )).unwrap();
 }
 
-// regex for unsafe characters, as defined in RFC 1738
-const RE_UNSAFE_CHARS: &str = r#"[{}|\\^~\[\]`<>"]"#;
-
-/// does the token contain characters not permitted by RFC 1738
-fn contains_unsafe_chars(token: &str) -> bool {
-    lazy_static! {
-        static ref UNSAFE: Regex = Regex::new(RE_UNSAFE_CHARS).unwrap();
-    }
-    UNSAFE.is_match(token)
+/// remove IRC formatting codes from a message
+fn strip_formatting(text: &str) -> Cow<'_, str> {
+    FORMATTING.replace_all(text, "")
 }
 
 /// does a message look like it contains a ping
@@ -353,7 +875,7 @@ fn is_ping(nick: &str, message: &str) -> bool {
 }
 
 /// create a name that doesn't trigger highlight regexes
-fn create_non_highlighting_name(name: &str) -> String {
+pub(crate) fn create_non_highlighting_name(name: &str) -> String {
     let mut graphemes = name.graphemes(true);
     let first = graphemes.next();
 
@@ -364,56 +886,14 @@ fn create_non_highlighting_name(name: &str) -> String {
         .collect()
 }
 
-/// truncate to a maximum number of bytes, taking UTF-8 into account
-fn utf8_truncate(s: &str, n: usize) -> String {
-    s.char_indices()
-        .take_while(|(len, c)| len + c.len_utf8() <= n)
-        .map(|(_, c)| c)
-        .collect()
-}
-
-lazy_static! {
-    static ref REPEATED_DOTS: Regex = Regex::new(r"\.\.+").unwrap();
-}
-
-/// if a token has a recognised TLD, but no scheme, add one
-pub fn add_scheme_for_tld(token: &str) -> Option<String> {
-    if token.parse::<Url>().is_err() {
-        if token.starts_with(|s: char| !s.is_alphabetic()) {
-            return None;
-        }
-
-        if REPEATED_DOTS.is_match(token) {
-            return None;
-        }
-
-        let new_token = format!("http://{}", token);
-
-        if let Ok(url) = new_token.parse::<Url>() {
-            if !url.domain()?.contains('.') {
-                return None;
-            }
-
-            // reject email addresses
-            if url.username() != "" {
-                return None;
-            }
-
-            let tld = url.domain()?
-                .split('.')
-                .last()?;
-
-            if TLD.contains(tld) {
-                return Some(new_token);
-            }
-        }
-    }
-
-    None
+/// check whether a channel is one of the status channels
+fn is_status_channel(rtd: &Rtd, channel: &str) -> bool {
+    let channel = irc_lowercase(channel);
+    param!(rtd, status_channels).iter().any(|c| irc_lowercase(c) == channel)
 }
 
 /// join any status channels not already joined and send a message to them
-pub fn msg_status_chans<S>(client: &IrcClient, rtd: &Rtd, msg: S)
+pub fn msg_status_chans<S>(queue: &SendQueue, rtd: &Rtd, msg: S)
 where
     S: ToString + std::fmt::Display,
 {
@@ -423,37 +903,48 @@ where
 
     param!(rtd, status_channels)
         .iter()
-        .for_each(|c| client.send_join(c).unwrap_or_else(|err| {
-            error!("Error joining status channel {}: {}", c, err)
-        }));
+        .for_each(|c| queue.send_join(c));
 
     param!(rtd, status_channels)
         .iter()
-        .for_each(|c| client.send_privmsg(c, &msg).unwrap());
+        .for_each(|c| send_fit(queue, rtd, "PRIVMSG", c, &msg.to_string()));
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread;
+    use std::{thread, sync::Once};
     use std::time::Duration;
     use tiny_http::Response;
-    use super::TitleResp::{Title, Error};
+    use super::TitleResp::{Title, Error, Throttled, Filtered};
+    use crate::config::Parameters;
+    use crate::sasl::Sasl;
+    use crate::template::Template;
+    use crate::sqlite::Database;
 
+    /// start the test HTTP server, once
     fn serve_html() {
-        let _ = thread::spawn(move || {
-            let srv = tiny_http::Server::http("127.0.0.1:28382").unwrap();
-            loop {
-                let rq = srv.recv().unwrap();
-                let resp = match rq.url() {
-                    "/empty" => Response::from_string(""),
-                    "/blank" => Response::from_string("<title></title>"),
-                    _ => Response::from_string("<title>|t|</title>"),
-                };
-                rq.respond(resp).unwrap();
-            }
+        static SERVER: Once = Once::new();
+
+        SERVER.call_once(|| {
+            let _ = thread::spawn(move || {
+                let srv = tiny_http::Server::http("127.0.0.1:28382").unwrap();
+                loop {
+                    let rq = srv.recv().unwrap();
+                    let resp = match rq.url() {
+                        "/empty" => Response::from_string(""),
+                        "/blank" => Response::from_string("<title></title>"),
+                        "/same-title" => Response::from_string("<title>Same title</title>"),
+                        u if u.starts_with("/canonical") => Response::from_string(
+                            "<title>c</title><link rel=\"canonical\" href=\"/canonical\">"
+                        ).with_header("Content-Type: text/html".parse::<tiny_http::Header>().unwrap()),
+                        _ => Response::from_string("<title>|t|</title>"),
+                    };
+                    rq.respond(resp).unwrap();
+                }
+            });
+            thread::sleep(Duration::from_millis(100));
         });
-        thread::sleep(Duration::from_millis(100));
     }
 
     fn pt(m: &str) -> Vec<TitleResp> {
@@ -471,13 +962,16 @@ mod tests {
     }
 
     fn pt_n(n: usize) -> Vec<TitleResp> {
+        pt(&pt_n_msg(n))
+    }
+
+    fn pt_n_msg(n: usize) -> String {
         let mut c = 0;
-        let m = iter::repeat("http://127.0.0.1:28382/")
+        iter::repeat("http://127.0.0.1:28382/")
             .take(n)
             .map(|t| {c += 1; format!("{}{}", t, c)})
             .collect::<Vec<String>>()
-            .join(" ");
-        pt(&m)
+            .join(" ")
     }
 
     #[test]
@@ -509,6 +1003,78 @@ mod tests {
         assert_eq!(10, pt_n(32).len());
     }
 
+    #[test]
+    fn test_process_titles_limit_channel_override() {
+        let mut rtd = Rtd::new().init_http_client().unwrap();
+        let over = toml::de::from_str("[parameters]\nurl_limit = 2").unwrap();
+        rtd.conf.channels.insert("#TestChannel".to_string(), over);
+        rtd.update_channels().unwrap();
+
+        assert_eq!(2, pt_with_rtd(&pt_n_msg(4), &rtd.for_channel("#testchannel")).len());
+        assert_eq!(4, pt_with_rtd(&pt_n_msg(4), &rtd.for_channel("#otherchannel")).len());
+    }
+
+    #[test]
+    fn test_process_titles_rate_limit() {
+        let mut rtd = Rtd::new().init_http_client().unwrap();
+        param!(rtd, rate_limit_user_burst) = 3;
+
+        let res = pt_with_rtd(&pt_n_msg(5), &rtd);
+        assert_eq!(4, res.len());
+        assert!(res[..3].iter().all(|r| matches!(r, Title(_))));
+        assert!(matches!(&res[3], Throttled(t) if t.starts_with("Sorry testnick,")));
+
+        // the notice is only sent once
+        assert!(pt_with_rtd(&pt_n_msg(1), &rtd).is_empty());
+
+        // other senders are not affected
+        let db = Database::open_in_memory().unwrap();
+        let msg = Msg::new(&rtd, "othernick", "#testchannel", "http://127.0.0.1:28382/");
+        assert_eq!(1, process_titles(&rtd, &db, &msg).count());
+
+        // per-channel limits
+        param!(rtd, rate_limit_channel_burst) = 1;
+        let text = pt_n_msg(2);
+        let msg = Msg::new(&rtd, "othernick", "#otherchannel", &text);
+        let res: Vec<_> = process_titles(&rtd, &db, &msg).collect();
+        assert_eq!(2, res.len());
+        assert!(matches!(&res[1], Throttled(t) if t.contains("#otherchannel")));
+    }
+
+    #[test]
+    fn test_process_titles_filter() {
+        let mut rtd = Rtd::new().init_http_client().unwrap();
+        rtd.conf.url_filter = toml::de::from_str(r#"
+            [[rules]]
+            action = "deny"
+            path = "/private"
+        "#).unwrap();
+
+        let m = "http://127.0.0.1:28382/private http://127.0.0.1:28382/public";
+        assert_eq!(vec![Title("⤷ |t|".into())], pt_with_rtd(m, &rtd));
+
+        rtd.conf.url_filter.message = "not allowed".into();
+        let m = "http://127.0.0.1:28382/private http://127.0.0.1:28382/private";
+        assert_eq!(vec![Filtered("not allowed".into())], pt_with_rtd(m, &rtd));
+
+        // channels may add rules
+        let over = toml::de::from_str(r#"
+            [url_filter]
+            default = "deny"
+            message = ""
+
+            [[url_filter.rules]]
+            action = "allow"
+            host = "127.0.0.1"
+        "#).unwrap();
+        rtd.conf.channels.insert("#testchannel".to_string(), over);
+        rtd.update_channels().unwrap();
+        let m = "http://127.0.0.1:28382/private http://localhost:28382/public";
+        assert_eq!(0, pt_with_rtd(m, &rtd.for_channel("#testchannel")).len());
+        let m = "http://127.0.0.1:28382/public";
+        assert_eq!(1, pt_with_rtd(m, &rtd.for_channel("#testchannel")).len());
+    }
+
     #[test]
     fn test_process_titles_value() {
         pt("http://127.0.0.1:28382/")
@@ -516,6 +1082,142 @@ mod tests {
             .for_each(|v| assert_eq!(&Title("⤷ |t|".to_string()), v));
     }
 
+    #[test]
+    fn test_process_titles_templates() {
+        let mut rtd = Rtd::new().init_http_client().unwrap();
+        feat!(rtd, history) = true;
+        rtd.conf.templates = toml::de::from_str(r#"
+            title = "[{domain}] {title} ({mime}, {size}) {{{user}}}"
+            repost = "{title} again, {prev_user} was first in {prev_channel}"
+            error = "{domain}: {error}"
+        "#).unwrap();
+
+        let db = Database::open_in_memory().unwrap();
+        let msg = Msg::new(&rtd, "nick", "#test", "http://127.0.0.1:28382/");
+
+        let res: Vec<_> = process_titles(&rtd, &db, &msg).collect();
+        assert_eq!(vec![Title("[127.0.0.1] |t| (text/plain, 18B) {nick}".into())], res);
+
+        let msg = Msg::new(&rtd, "other", "#test", "http://127.0.0.1:28382/");
+        let res: Vec<_> = process_titles(&rtd, &db, &msg).collect();
+        assert_eq!(vec![Title("|t| again, nick was first in #test".into())], res);
+
+        // every post is counted
+        rtd.conf.templates.repost = Template::parse(
+            "{title} posted {count} times, by {prev_user} {prev_ago}, and {last_user} {last_ago}"
+        ).unwrap();
+        let msg = Msg::new(&rtd, "third", "#test", "http://127.0.0.1:28382/");
+        let res: Vec<_> = process_titles(&rtd, &db, &msg).collect();
+        assert_eq!(vec![Title("|t| posted 2 times, by nick just now, and other just now".into())], res);
+
+        let msg = Msg::new(&rtd, "nick", "#test", "http://127.0.0.1:28382/empty");
+        let res: Vec<_> = process_titles(&rtd, &db, &msg).collect();
+        assert!(err_val(&res[0],
+            "127.0.0.1: http://127.0.0.1:28382/empty: failed to parse title"));
+    }
+
+    #[test]
+    fn test_process_titles_shared() {
+        serve_html();
+        let db = Database::open_in_memory().unwrap();
+        let rtd = |net: &str| {
+            let mut rtd = Rtd::new().init_http_client().unwrap();
+            rtd.conf.network.name = net.into();
+            feat!(rtd, history) = true;
+            feat!(rtd, cross_channel_history) = true;
+            rtd.conf.templates.repost = Template::parse(
+                "{title} by {prev_user} ({prev_location}) {prev_network}, last in {last_location}"
+            ).unwrap();
+            rtd
+        };
+        let (mut foo, mut bar) = (rtd("foo"), rtd("bar"));
+        let url = "http://127.0.0.1:28382/shared";
+        let post = |rtd: &Rtd, nick, chan| {
+            process_titles(rtd, &db, &Msg::new(rtd, nick, chan, url)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec![Title("⤷ |t|".into())], post(&foo, "a", "#one"));
+
+        // posts on other networks are only reported if the history is shared
+        assert_eq!(vec![Title("⤷ |t|".into())], post(&bar, "b", "#two"));
+        bar.conf.database.shared = true;
+        assert_eq!(
+            vec![Title("|t| by a (#one on foo) foo, last in #two".into())],
+            post(&bar, "c", "#two")
+        );
+        foo.conf.database.shared = true;
+        assert_eq!(
+            vec![Title("|t| by a (#one) foo, last in #two on bar".into())],
+            post(&foo, "d", "#one")
+        );
+
+        // and only to the same channel, if history is not cross-channel
+        feat!(bar, cross_channel_history) = false;
+        assert_eq!(
+            vec![Title("|t| by b (#two) bar, last in #two".into())],
+            post(&bar, "e", "#two")
+        );
+    }
+
+    #[test]
+    fn test_process_titles_url_similarity() {
+        let mut rtd = Rtd::new().init_http_client().unwrap();
+        let m = "http://127.0.0.1:28382/same-title http://127.0.0.1:28382/other";
+
+        assert_eq!(2, pt_with_rtd(m, &rtd).len());
+
+        param!(rtd, title_url_similarity) = 0.8;
+        assert_eq!(vec![Title("⤷ |t|".into())], pt_with_rtd(m, &rtd));
+
+        // suppressed titles are still logged
+        feat!(rtd, history) = true;
+        let msg = Msg::new(&rtd, "testnick", "#test", m);
+        let db = Database::open_in_memory().unwrap();
+        process_titles(&rtd, &db, &msg).for_each(drop);
+        let network = &rtd.conf.network.name;
+        assert_eq!(2, db.recent_posts(network, "#test", 10).unwrap().len());
+    }
+
+    #[test]
+    fn test_process_titles_cache() {
+        let rtd = Rtd::new().init_http_client().unwrap().cache(TitleCache::new(10));
+        let m = "http://127.0.0.1:28382/ http://127.0.0.1:28382/empty";
+
+        let titles = pt_with_rtd(m, &rtd);
+        assert_eq!(2, titles.len());
+        assert_eq!((0, 2), (rtd.cache.stats().hits, rtd.cache.stats().misses));
+
+        // titles and errors are cached, ignoring any fragment
+        assert_eq!(titles, pt_with_rtd(m, &rtd));
+        let m = "http://127.0.0.1:28382/#fragment";
+        assert_eq!(titles[..1], pt_with_rtd(m, &rtd)[..]);
+        assert_eq!((3, 2), (rtd.cache.stats().hits, rtd.cache.stats().misses));
+    }
+
+    #[test]
+    fn test_process_titles_cache_settings() {
+        let cache = TitleCache::new(10);
+        let rtd = Rtd::new().init_http_client().unwrap().cache(cache.clone());
+        let mut other = rtd.clone();
+        other.conf.network.name = "other".into();
+        let m = "http://127.0.0.1:28382/";
+
+        // networks which look up titles in the same way share cached titles
+        pt_with_rtd(m, &rtd);
+        pt_with_rtd(m, &other);
+        assert_eq!((1, 1), (cache.stats().hits, cache.stats().misses));
+
+        // but not with those which differ in settings affecting the results
+        feat!(other, report_mime) = true;
+        pt_with_rtd(m, &other);
+        other.conf.http_params.user_agent = Some("other".into());
+        pt_with_rtd(m, &other);
+        other.conf.plugins = toml::de::from_str("[youtube]\napi_key = \"k\"").unwrap();
+        pt_with_rtd(m, &other);
+        assert_eq!((1, 4), (cache.stats().hits, cache.stats().misses));
+        assert_eq!(4, cache.stats().entries);
+    }
+
     #[test]
     fn test_process_titles_repost() {
         let mut rtd = Rtd::new().init_http_client().unwrap();
@@ -598,6 +1300,29 @@ mod tests {
             });
     }
 
+    #[test]
+    fn test_process_titles_canonical() {
+        serve_html();
+        let m = "http://127.0.0.1:28382/a?utm_source=x http://127.0.0.1:28382/a/";
+        assert_eq!(1, pt(m).len());
+
+        // pages with the same canonical link are the same
+        let m = "http://127.0.0.1:28382/canonical?page=1 http://127.0.0.1:28382/canonical?page=2";
+        assert_eq!(1, pt(m).len());
+
+        // reposts are found by the canonical key
+        let mut rtd = Rtd::new().init_http_client().unwrap();
+        feat!(rtd, history) = true;
+        let db = Database::open_in_memory().unwrap();
+        let msg = Msg::new(&rtd, "testnick", "#test", "http://127.0.0.1:28382/b?fbclid=x");
+        let res: Vec<_> = process_titles(&rtd, &db, &msg).collect();
+        assert_eq!(vec![Title("⤷ |t|".to_string())], res);
+
+        let msg = Msg::new(&rtd, "othernick", "#test", "http://127.0.0.1:28382/b/");
+        let res: Vec<_> = process_titles(&rtd, &db, &msg).collect();
+        assert!(matches!(&res[0], Title(t) if t.ends_with(" testnick (#test)")));
+    }
+
     #[test]
     fn test_process_titles_http_https_only() {
         assert_eq!(0, pt("git://127.0.0.1:28382/").len());
@@ -610,6 +1335,18 @@ mod tests {
         assert_eq!(0, pt("http://127.0.0.1:28382/{}").len());
     }
 
+    #[test]
+    fn test_process_titles_in_text() {
+        for m in &[
+            "(http://127.0.0.1:28382/)",
+            "see <http://127.0.0.1:28382/>.",
+            "[link](http://127.0.0.1:28382/)",
+            "\"http://127.0.0.1:28382/\", he said",
+        ] {
+            assert_eq!(vec![Title("⤷ |t|".to_string())], pt(m));
+        }
+    }
+
     fn err_val(r: &TitleResp, s: &str) -> bool {
         if let Error(st) = r {
             st == s
@@ -624,6 +1361,33 @@ mod tests {
             "http://127.0.0.1:28382/blank: failed to parse title"));
     }
 
+    #[test]
+    fn test_process_titles_failing() {
+        serve_html();
+        let mut rtd = Rtd::new().init_http_client().unwrap();
+        rtd.conf.http_params.failure_limit = 2;
+        let db = Database::open_in_memory().unwrap();
+        let msg = Msg::new(&rtd, "testnick", "#test", "http://127.0.0.1:28382/empty");
+
+        for _ in 0..2 {
+            let res: Vec<_> = process_titles(&rtd, &db, &msg).collect();
+            assert!(matches!(&res[0], Error(_)));
+        }
+
+        // the URL is no longer looked up, but others on its host are
+        assert!(process_titles(&rtd, &db, &msg).next().is_none());
+        let other = Msg::new(&rtd, "testnick", "#test", "http://127.0.0.1:28382/");
+        assert_eq!(1, process_titles(&rtd, &db, &other).count());
+
+        let since = Utc::now() - chrono::Duration::minutes(1);
+        let hosts = db.failing_hosts(since, 10).unwrap();
+        assert_eq!(1, hosts.len());
+        assert_eq!((2, Some("no-title")), (hosts[0].count, hosts[0].last_kind.as_deref()));
+
+        rtd.conf.http_params.failure_limit = 0;
+        assert_eq!(1, process_titles(&rtd, &db, &msg).count());
+    }
+
     #[test]
     #[ignore]
     fn test_process_titles_partial() {
@@ -660,6 +1424,108 @@ mod tests {
         assert!(pt_with_rtd("http://127.0.0.1:28382/", &rtd).is_empty());
     }
 
+    fn queue() -> SendQueue {
+        let client = IrcClient::from_config(irc::client::data::Config {
+            nickname: Some("url-bot-rs".into()),
+            use_mock_connection: Some(true),
+            ..irc::client::data::Config::default()
+        }).unwrap();
+
+        SendQueue::new(client, &Parameters::default())
+    }
+
+    #[test]
+    fn test_track_userhost() {
+        let queue = queue();
+        let mut rtd = Rtd::default();
+
+        let mut track = |raw: &str| {
+            track_userhost(&queue, &mut rtd, &raw.parse().unwrap());
+            rtd.userhost.clone()
+        };
+
+        assert_eq!(None, track(":irc.example.com 001 url-bot-rs :Welcome url-bot-rs"));
+        assert_eq!(Some("~bot@host.example.com".into()),
+            track(":irc.example.com 001 url-bot-rs :Welcome url-bot-rs!~bot@host.example.com"));
+        assert_eq!(Some("~bot@host.example.com".into()),
+            track(":other!~o@elsewhere JOIN #chan"));
+        assert_eq!(Some("~bot@1.2.3.4".into()),
+            track(":url-bot-rs!~bot@1.2.3.4 JOIN #chan"));
+        assert_eq!(Some("~bot@cloaked".into()),
+            track(":irc.example.com 396 url-bot-rs cloaked :is now your displayed host"));
+        assert_eq!(Some("bot@new.host".into()),
+            track(":url-bot-rs!~bot@cloaked CHGHOST bot new.host"));
+    }
+
+    #[test]
+    fn test_is_replay() {
+        let queue = queue();
+        let mut rtd = Rtd::default();
+        let replay = |rtd: &Rtd, raw: &str| is_replay(&queue, rtd, &raw.parse().unwrap());
+
+        assert!(!replay(&rtd, ":nick!u@h PRIVMSG #chan :http://example.com"));
+        assert!(replay(&rtd, ":URL-bot-rs!u@h PRIVMSG #chan :⤷ title"));
+        assert!(replay(&rtd, ":url-bot-rs!u@h NOTICE #chan :⤷ title"));
+        assert!(!replay(&rtd, ":url-bot-rs!u@h JOIN #chan"));
+
+        rtd.batches.handle(&":irc.test BATCH +h chathistory #chan".parse().unwrap());
+        assert!(replay(&rtd, "@batch=h :nick!u@h PRIVMSG #chan :http://example.com"));
+        assert!(!replay(&rtd, "@batch=x :nick!u@h PRIVMSG #chan :http://example.com"));
+    }
+
+    #[test]
+    fn test_reply_to() {
+        let mut rtd = Rtd::default();
+        let mut msg = Msg::new(&rtd, "nick", "#chan", "http://example.com");
+        let tags = |rtd: &Rtd, msg: &Msg| {
+            reply_to(rtd, msg, Command::PRIVMSG("#chan".into(), "title".into())).tags
+        };
+
+        assert_eq!(None, tags(&rtd, &msg));
+
+        msg.tags = Tags::new(&"@msgid=a\\sb :nick!u@h PRIVMSG #chan :hi".parse().unwrap());
+        assert_eq!(None, tags(&rtd, &msg));
+
+        for raw in &[":irc.test CAP * LS :message-tags", ":irc.test CAP * ACK :message-tags"] {
+            let m: Message = raw.parse().unwrap();
+            rtd.caps.handle(&m.command, &Sasl::default()).unwrap();
+        }
+        assert_eq!(Some(vec![Tag("+draft/reply".into(), Some("a\\sb".into()))]),
+            tags(&rtd, &msg));
+    }
+
+    #[test]
+    fn test_is_status_channel() {
+        let mut rtd = Rtd::default();
+        assert!(!is_status_channel(&rtd, "#status"));
+
+        param!(rtd, status_channels) = vec!["#Status[1]".into()];
+        assert!(is_status_channel(&rtd, "#status[1]"));
+        assert!(is_status_channel(&rtd, "#STATUS{1}"));
+        assert!(!is_status_channel(&rtd, "#status"));
+    }
+
+    #[test]
+    fn test_fit() {
+        let queue = queue();
+        let mut rtd = Rtd::default();
+        let title = "word ".repeat(400);
+
+        let lines = fit(&queue, &rtd, "PRIVMSG", "#chan", &title);
+        assert_eq!(1, lines.len());
+        assert!(lines[0].ends_with("word…"));
+        // ":url-bot-rs!<74 bytes> PRIVMSG #chan :"
+        assert!(lines[0].len() <= 510 - 98);
+
+        rtd.userhost = Some("u@h".into());
+        param!(rtd, continuation_lines) = 2;
+        let lines = fit(&queue, &rtd, "PRIVMSG", "#chan", &title);
+        assert_eq!(3, lines.len());
+        assert!(lines.iter().all(|l| l.len() <= 510 - 27));
+        assert!(lines[..2].iter().all(|l| l.ends_with("word")));
+        assert!(lines[2].ends_with('…'));
+    }
+
     #[test]
     fn test_is_ping() {
         assert_eq!(is_ping("a", "a"), true);
@@ -702,18 +1568,48 @@ mod tests {
     }
 
     #[test]
-    fn test_utf8_truncate() {
-        assert_eq!("", utf8_truncate("", 10));
-        assert_eq!("", utf8_truncate("", 1));
-        assert_eq!(" ", utf8_truncate("  ", 1));
-        assert_eq!("\u{2665}", utf8_truncate("\u{2665}", 4));
-        assert_eq!("\u{2665}", utf8_truncate("\u{2665}", 3));
-        assert_eq!("", utf8_truncate("\u{2665}", 2));
-        assert_eq!("\u{0306}\u{0306}", utf8_truncate("\u{0306}\u{0306}", 4));
-        assert_eq!("\u{0306}", utf8_truncate("\u{0306}\u{0306}", 2));
-        assert_eq!("\u{0306}", utf8_truncate("\u{0306}", 2));
-        assert_eq!("", utf8_truncate("\u{0306}", 1));
-        assert_eq!("hello ", utf8_truncate("hello \u{1F603} world!", 9));
+    fn test_strip_formatting() {
+        for (raw, stripped) in &[
+            ("", ""),
+            ("no formatting", "no formatting"),
+            ("\x02bold\x02", "bold"),
+            ("\x1Ditalic\x1D", "italic"),
+            ("\x1Funderline\x1F", "underline"),
+            ("\x1Estrikethrough\x1E", "strikethrough"),
+            ("\x11monospace\x11", "monospace"),
+            ("\x16reverse\x16", "reverse"),
+            ("\x02bold\x0F reset", "bold reset"),
+            ("\x03colour", "colour"),
+            ("\x034red\x03", "red"),
+            ("\x0304red\x03", "red"),
+            ("\x034,5red on brown\x03", "red on brown"),
+            ("\x0304,05red on brown\x03", "red on brown"),
+            ("\x0312,1blue", "blue"),
+            ("\x03123", "3"),
+            ("\x034,", ","),
+            ("\x034,x", ",x"),
+            ("\x03,4", ",4"),
+            ("\x04FF0000red\x04", "red"),
+            ("\x04ff0000,00ff00red on green\x04", "red on green"),
+            ("\x04FF00red", "FF00red"),
+            ("\x02\x0304https://a.bc/d\x03\x02", "https://a.bc/d"),
+            ("see \x1Fhttps://a.bc/d\x1F.", "see https://a.bc/d."),
+            ("\x0314,99\x02\x1D\x1F\x16\x11\x1E\x0Ftext", "text"),
+        ] {
+            assert_eq!(*stripped, strip_formatting(raw));
+        }
+    }
+
+    #[test]
+    fn test_process_titles_formatting() {
+        for m in &[
+            "\x02http://127.0.0.1:28382/\x02",
+            "\x0304,01http://127.0.0.1:28382/\x03",
+            "\x04FF0000http://127.0.0.1:28382/\x0F",
+            "\x1F\x1Dhttp://127.0.0.1:28382/\x1D\x1F",
+        ] {
+            assert_eq!(vec![Title("⤷ |t|".to_string())], pt(m));
+        }
     }
 
     #[test]
@@ -723,65 +1619,4 @@ mod tests {
         assert_eq!("b\u{200C}ar", create_non_highlighting_name("bar"));
         assert_eq!("b\u{200C}az", create_non_highlighting_name("baz"));
     }
-
-    #[test]
-    fn test_contains_unsafe_chars() {
-        for c in &['{', '}', '|', '\\', '^', '~', '[', ']', '`', '<', '>', '"']
-        {
-            assert!(contains_unsafe_chars(&format!("http://z/{}", c)));
-        }
-        assert_eq!(contains_unsafe_chars("http://z.zzz/"), false);
-    }
-
-    #[test]
-    fn test_add_scheme_for_tld() {
-        // appears to be a URL, and has a valid TLD
-        assert!(add_scheme_for_tld("docs.rs").is_some());
-        assert!(add_scheme_for_tld("nomnomnom.xyz").is_some());
-        assert!(add_scheme_for_tld("endless.horse").is_some());
-        assert!(add_scheme_for_tld("google.co.uk").is_some());
-        assert!(add_scheme_for_tld("notreal.co.uk/#banana").is_some());
-        assert!(add_scheme_for_tld("notreal.co.uk/?banana=3").is_some());
-
-        // return value is as expected
-        assert_eq!(
-            Some(String::from("http://nomnomnom.xyz")),
-            add_scheme_for_tld("nomnomnom.xyz")
-        );
-        assert_eq!(
-            Some(String::from("http://google.co.uk")),
-            add_scheme_for_tld("google.co.uk")
-        );
-
-        // already a valid URL
-        assert!(add_scheme_for_tld("http://nomnomnom.xyz").is_none());
-        assert!(add_scheme_for_tld("http://endless.horse").is_none());
-
-        // not a recognised TLD
-        assert!(add_scheme_for_tld("abc.cheese").is_none());
-        assert!(add_scheme_for_tld("abc.limes").is_none());
-
-        // recognised TLD, but incomplete as a URL
-        assert!(add_scheme_for_tld("xyz").is_none());
-        assert!(add_scheme_for_tld("uk").is_none());
-        assert!(add_scheme_for_tld("horse").is_none());
-
-        // don't resolve email addresses
-        assert!(add_scheme_for_tld("test@gmail.com").is_none());
-        assert!(add_scheme_for_tld("word.word@gmail.com").is_none());
-
-        // don't resolve tokens beinning with @
-        assert!(add_scheme_for_tld("@gmail.com").is_none());
-        assert!(add_scheme_for_tld("@endless.horse").is_none());
-
-        // don't resolve tokens beginning with '.'
-        assert!(add_scheme_for_tld(".net").is_none());
-        assert!(add_scheme_for_tld(".zip").is_none());
-        assert!(add_scheme_for_tld("...cool").is_none());
-
-        // don't resolve tokens containing repeated full-stops
-        assert_eq!(None, add_scheme_for_tld("wow...cool"));
-        assert_eq!(None, add_scheme_for_tld("something..wow"));
-        assert_eq!(None, add_scheme_for_tld("something.....boo"));
-    }
 }
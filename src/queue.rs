@@ -0,0 +1,507 @@
+/*
+ * Outgoing message queue
+ *
+ */
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex, Condvar},
+    thread,
+    time::{Duration, Instant},
+};
+use irc::client::prelude::*;
+use log::{debug, error};
+
+use crate::config::Parameters;
+
+/// Pacing of outgoing lines, as a token bucket
+#[derive(Debug)]
+struct Pacer {
+    /// number of lines which may be sent in quick succession
+    burst: f64,
+    /// lines per second, after the burst is used up, zero disables pacing
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Pacer {
+    fn new(burst: u32, rate: f64, now: Instant) -> Self {
+        let burst = f64::from(burst.max(1));
+
+        Self {
+            burst,
+            rate,
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    /// get the time to wait before the next line may be sent, if any
+    fn wait(&mut self, now: Instant) -> Option<Duration> {
+        if self.rate <= 0.0 {
+            return None;
+        }
+
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    /// record that a line was sent
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+#[derive(Debug)]
+struct Queued {
+    message: Message,
+    time: Instant,
+}
+
+/// What to do next when draining the queue
+#[derive(Debug, PartialEq)]
+enum Next {
+    Send(Message),
+    Wait(Duration),
+    Empty,
+}
+
+/// Ordering of lines sent by ordered queues with the same key
+#[derive(Debug, Default)]
+struct Order {
+    /// next ticket to issue
+    issued: u64,
+    /// next ticket whose lines may be queued
+    next: u64,
+    /// lines of finished tickets, waiting for earlier tickets to finish
+    held: BTreeMap<u64, Vec<Message>>,
+}
+
+#[derive(Debug)]
+struct State {
+    lines: VecDeque<Queued>,
+    orders: HashMap<String, Order>,
+    pacer: Pacer,
+    /// responses older than this are dropped, zero disables
+    max_age: Duration,
+    closed: bool,
+}
+
+impl State {
+    fn new(params: &Parameters, now: Instant) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            orders: HashMap::new(),
+            pacer: Pacer::new(params.send_burst, params.send_rate, now),
+            max_age: Duration::from_secs(params.send_max_age_s),
+            closed: false,
+        }
+    }
+
+    /// add a line to the queue, unless the same line is already queued
+    fn push(&mut self, message: Message, now: Instant) -> bool {
+        if self.lines.iter().any(|q| q.message == message) {
+            return false;
+        }
+
+        self.lines.push_back(Queued { message, time: now });
+        true
+    }
+
+    /// issue a ticket for an ordered queue
+    fn ticket(&mut self, key: &str) -> u64 {
+        let order = self.orders.entry(key.to_string()).or_default();
+        order.issued += 1;
+        order.issued - 1
+    }
+
+    /// queue the lines of a finished ticket, and those of any later tickets
+    /// which were waiting for it
+    fn finish(&mut self, key: &str, ticket: u64, lines: Vec<Message>, now: Instant) -> bool {
+        let order = match self.orders.get_mut(key) {
+            Some(order) => order,
+            None => return false,
+        };
+
+        order.held.insert(ticket, lines);
+
+        let mut ready = vec![];
+        while let Some(lines) = order.held.remove(&order.next) {
+            ready.extend(lines);
+            order.next += 1;
+        }
+
+        if order.next == order.issued {
+            self.orders.remove(key);
+        }
+
+        let mut pushed = false;
+        for line in ready {
+            pushed |= self.push(line, now);
+        }
+        pushed
+    }
+
+    /// is a queued line a response which has waited too long to be sent
+    fn is_stale(&self, queued: &Queued, now: Instant) -> bool {
+        let is_response = matches!(
+            queued.message.command,
+            Command::PRIVMSG(..) | Command::NOTICE(..)
+        );
+
+        is_response
+            && self.max_age > Duration::from_secs(0)
+            && now.saturating_duration_since(queued.time) > self.max_age
+    }
+
+    /// take the next line to send, dropping any stale lines
+    fn next(&mut self, now: Instant) -> Next {
+        while let Some(queued) = self.lines.front() {
+            if !self.is_stale(queued, now) {
+                break;
+            }
+            let queued = self.lines.pop_front().unwrap();
+            debug!("dropping stale line: {:?}", queued.message);
+        }
+
+        if self.lines.is_empty() {
+            return Next::Empty;
+        }
+
+        if let Some(wait) = self.pacer.wait(now) {
+            return Next::Wait(wait);
+        }
+
+        self.pacer.take();
+        Next::Send(self.lines.pop_front().unwrap().message)
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// Closes the queue when the last handle is dropped
+struct Handle {
+    shared: Arc<Shared>,
+    client: IrcClient,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.cond.notify_all();
+    }
+}
+
+/// Lines sent by an ordered queue, queued once it has been dropped
+struct Ticket {
+    shared: Arc<Shared>,
+    key: String,
+    number: u64,
+    lines: Mutex<Vec<Message>>,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let lines = std::mem::take(&mut *self.lines.lock().unwrap());
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.finish(&self.key, self.number, lines, Instant::now()) {
+            self.shared.cond.notify_all();
+        }
+    }
+}
+
+/// Queue of lines to send to an IRC server, paced to avoid being
+/// disconnected for flooding
+///
+/// Lines are sent by a background thread, which exits once all clones of
+/// the queue have been dropped.
+#[derive(Clone)]
+pub struct SendQueue {
+    handle: Arc<Handle>,
+    ticket: Option<Arc<Ticket>>,
+}
+
+impl SendQueue {
+    pub fn new(client: IrcClient, params: &Parameters) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::new(params, Instant::now())),
+            cond: Condvar::new(),
+        });
+
+        let (thread_shared, thread_client) = (shared.clone(), client.clone());
+        thread::spawn(move || run(&thread_shared, &thread_client));
+
+        Self {
+            handle: Arc::new(Handle { shared, client }),
+            ticket: None,
+        }
+    }
+
+    /// Get a queue whose lines are held until it, and all clones of it,
+    /// have been dropped, and are then sent after those of any ordered
+    /// queues with the same key created before it
+    ///
+    /// This keeps responses in order when they're generated concurrently.
+    pub fn ordered(&self, key: &str) -> Self {
+        let shared = &self.handle.shared;
+        let number = shared.state.lock().unwrap().ticket(key);
+
+        Self {
+            handle: self.handle.clone(),
+            ticket: Some(Arc::new(Ticket {
+                shared: shared.clone(),
+                key: key.to_string(),
+                number,
+                lines: Mutex::new(vec![]),
+            })),
+        }
+    }
+
+    /// the client lines are sent with
+    pub fn client(&self) -> &IrcClient {
+        &self.handle.client
+    }
+
+    /// queue a message to be sent, which may have tags
+    pub fn send_message(&self, message: Message) {
+        if let Some(ticket) = &self.ticket {
+            ticket.lines.lock().unwrap().push(message);
+            return;
+        }
+
+        let shared = &self.handle.shared;
+
+        if shared.state.lock().unwrap().push(message, Instant::now()) {
+            shared.cond.notify_all();
+        }
+    }
+
+    /// queue a command to be sent
+    pub fn send(&self, command: Command) {
+        self.send_message(command.into());
+    }
+
+    pub fn send_privmsg<S: ToString, T: ToString>(&self, target: S, text: T) {
+        self.send(Command::PRIVMSG(target.to_string(), text.to_string()));
+    }
+
+    pub fn send_notice<S: ToString, T: ToString>(&self, target: S, text: T) {
+        self.send(Command::NOTICE(target.to_string(), text.to_string()));
+    }
+
+    pub fn send_join<S: ToString>(&self, channel: S) {
+        self.send(Command::JOIN(channel.to_string(), None, None));
+    }
+
+    pub fn send_part<S: ToString>(&self, channel: S) {
+        self.send(Command::PART(channel.to_string(), None));
+    }
+}
+
+/// send queued lines, until the queue is closed
+fn run(shared: &Shared, client: &IrcClient) {
+    let mut state = shared.state.lock().unwrap();
+
+    loop {
+        if state.closed {
+            return;
+        }
+
+        match state.next(Instant::now()) {
+            Next::Empty => {
+                state = shared.cond.wait(state).unwrap();
+            },
+            Next::Wait(wait) => {
+                state = shared.cond.wait_timeout(state, wait).unwrap().0;
+            },
+            Next::Send(message) => {
+                drop(state);
+                if let Err(e) = client.send(message) {
+                    error!("error sending message: {}", e);
+                }
+                state = shared.state.lock().unwrap();
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use irc::proto::message::Tag;
+
+    fn params(burst: u32, rate: f64, max_age_s: u64) -> Parameters {
+        Parameters {
+            send_burst: burst,
+            send_rate: rate,
+            send_max_age_s: max_age_s,
+            ..Parameters::default()
+        }
+    }
+
+    fn msg(text: &str) -> Message {
+        Command::PRIVMSG("#chan".into(), text.into()).into()
+    }
+
+    #[test]
+    fn test_pacer() {
+        let now = Instant::now();
+        let mut p = Pacer::new(3, 0.5, now);
+
+        for _ in 0..3 {
+            assert_eq!(None, p.wait(now));
+            p.take();
+        }
+        assert_eq!(Some(Duration::from_secs(2)), p.wait(now));
+        assert_eq!(Some(Duration::from_secs(1)), p.wait(now + Duration::from_secs(1)));
+        assert_eq!(None, p.wait(now + Duration::from_secs(2)));
+        p.take();
+
+        // tokens don't accumulate beyond the burst size
+        let later = now + Duration::from_secs(100);
+        for _ in 0..3 {
+            assert_eq!(None, p.wait(later));
+            p.take();
+        }
+        assert!(p.wait(later).is_some());
+    }
+
+    #[test]
+    fn test_pacer_disabled() {
+        let now = Instant::now();
+        let mut p = Pacer::new(0, 0.0, now);
+
+        for _ in 0..100 {
+            assert_eq!(None, p.wait(now));
+            p.take();
+        }
+    }
+
+    #[test]
+    fn test_queue_pacing() {
+        let now = Instant::now();
+        let mut s = State::new(&params(2, 1.0, 0), now);
+
+        assert_eq!(Next::Empty, s.next(now));
+
+        for n in 0..4 {
+            assert!(s.push(msg(&n.to_string()), now));
+        }
+
+        assert_eq!(Next::Send(msg("0")), s.next(now));
+        assert_eq!(Next::Send(msg("1")), s.next(now));
+        assert_eq!(Next::Wait(Duration::from_secs(1)), s.next(now));
+
+        let now = now + Duration::from_secs(1);
+        assert_eq!(Next::Send(msg("2")), s.next(now));
+        assert!(matches!(s.next(now), Next::Wait(_)));
+
+        let now = now + Duration::from_secs(1);
+        assert_eq!(Next::Send(msg("3")), s.next(now));
+        assert_eq!(Next::Empty, s.next(now));
+    }
+
+    #[test]
+    fn test_queue_coalesce() {
+        let now = Instant::now();
+        let mut s = State::new(&params(10, 1.0, 0), now);
+
+        assert!(s.push(msg("a"), now));
+        assert!(!s.push(msg("a"), now));
+        assert!(s.push(msg("b"), now));
+        assert!(s.push(Command::NOTICE("#chan".into(), "a".into()).into(), now));
+        assert!(s.push(Command::PRIVMSG("#other".into(), "a".into()).into(), now));
+
+        // the same line, replying to a different message
+        let mut reply = msg("a");
+        reply.tags = Some(vec![Tag("+draft/reply".into(), Some("abc".into()))]);
+        assert!(s.push(reply, now));
+        assert_eq!(5, s.lines.len());
+
+        // once sent, the same line may be queued again
+        assert_eq!(Next::Send(msg("a")), s.next(now));
+        assert!(s.push(msg("a"), now));
+    }
+
+    #[test]
+    fn test_queue_order() {
+        let now = Instant::now();
+        let mut s = State::new(&params(10, 1.0, 0), now);
+
+        let (a0, a1, a2) = (s.ticket("#a"), s.ticket("#a"), s.ticket("#a"));
+        let b0 = s.ticket("#b");
+
+        // later tickets wait for earlier ones with the same key
+        assert!(!s.finish("#a", a1, vec![msg("a1")], now));
+        assert!(!s.finish("#a", a2, vec![], now));
+        assert!(s.finish("#b", b0, vec![msg("b0")], now));
+        assert!(s.finish("#a", a0, vec![msg("a0"), msg("a0.1")], now));
+
+        for text in &["b0", "a0", "a0.1", "a1"] {
+            assert_eq!(Next::Send(msg(text)), s.next(now));
+        }
+        assert_eq!(Next::Empty, s.next(now));
+        assert!(s.orders.is_empty());
+
+        // tickets are issued afresh once all have finished
+        assert_eq!(0, s.ticket("#a"));
+    }
+
+    #[test]
+    fn test_ordered_queue() {
+        let client = IrcClient::from_config(Config {
+            nickname: Some("test".into()),
+            use_mock_connection: Some(true),
+            ..Config::default()
+        }).unwrap();
+        let queue = SendQueue::new(client, &params(10, 0.0, 0));
+        let lines = || queue.handle.shared.state.lock().unwrap().lines.len();
+
+        let first = queue.ordered("#chan");
+        let second = queue.ordered("#chan");
+        second.send_privmsg("#chan", "second");
+        first.send_privmsg("#chan", "first");
+
+        let held = second.clone();
+        drop(second);
+        drop(held);
+        assert_eq!(0, lines());
+
+        // lines are sent once the first queue is dropped, so may be gone
+        drop(first);
+        assert!(queue.handle.shared.state.lock().unwrap().orders.is_empty());
+    }
+
+    #[test]
+    fn test_queue_stale() {
+        let now = Instant::now();
+        let mut s = State::new(&params(1, 0.1, 5), now);
+        let join: Message = Command::JOIN("#chan".into(), None, None).into();
+
+        s.push(msg("a"), now);
+        s.push(msg("b"), now);
+        s.push(join.clone(), now);
+        s.push(msg("c"), now + Duration::from_secs(4));
+
+        assert_eq!(Next::Send(msg("a")), s.next(now));
+
+        // "b" is dropped, but joins are never stale
+        let now = now + Duration::from_secs(10);
+        assert_eq!(Next::Send(join), s.next(now));
+        assert_eq!(1, s.lines.len());
+
+        let now = now + Duration::from_secs(10);
+        assert_eq!(Next::Empty, s.next(now));
+    }
+}
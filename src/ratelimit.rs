@@ -0,0 +1,284 @@
+/*
+ * Rate limiting of title lookups
+ *
+ */
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::{Parameters, irc_lowercase};
+
+/// number of buckets above which full buckets are discarded
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Limits for a single kind of bucket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    /// maximum number of lookups which may be made in quick succession,
+    /// zero disables the limit
+    pub burst: u32,
+    /// time taken to regain a single lookup
+    pub refill: Duration,
+}
+
+impl Limit {
+    fn enabled(&self) -> bool {
+        self.burst > 0 && self.refill > Duration::from_secs(0)
+    }
+}
+
+/// Limits applying per user, and per channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub user: Limit,
+    pub channel: Limit,
+}
+
+impl Limits {
+    pub fn from_params(params: &Parameters) -> Self {
+        Self {
+            user: Limit {
+                burst: params.rate_limit_user_burst,
+                refill: Duration::from_secs(params.rate_limit_user_refill_s),
+            },
+            channel: Limit {
+                burst: params.rate_limit_channel_burst,
+                refill: Duration::from_secs(params.rate_limit_channel_refill_s),
+            },
+        }
+    }
+}
+
+/// The outcome of checking a lookup against the rate limits
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Allow,
+    /// the user has exceeded their limit, and should be told so if this is
+    /// the first lookup refused since they were last allowed one
+    UserThrottled { notify: bool },
+    /// the channel has exceeded its limit, notification as for users
+    ChannelThrottled { notify: bool },
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: &Limit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    /// add tokens accrued since the bucket was last updated
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        let accrued = elapsed.as_secs_f64() / limit.refill.as_secs_f64();
+        self.tokens = (self.tokens + accrued).min(f64::from(limit.burst));
+        self.updated = now;
+    }
+
+    fn is_full(&self, limit: &Limit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated);
+        let accrued = elapsed.as_secs_f64() / limit.refill.as_secs_f64();
+        self.tokens + accrued >= f64::from(limit.burst)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    users: HashMap<String, Bucket>,
+    channels: HashMap<String, Bucket>,
+    /// keys which have already been notified of throttling
+    notified: HashSet<String>,
+}
+
+/// Token bucket rate limiter, keyed by sender and by channel
+///
+/// Clones share state, so that limits persist between messages.
+#[derive(Debug, Default, Clone)]
+pub struct RateLimiter {
+    state: Arc<Mutex<State>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check whether a lookup may be made for a URL posted by a user in a
+    /// channel, consuming a token from each applicable bucket if so.
+    pub fn check(&self, limits: &Limits, user: &str, channel: &str) -> Decision {
+        self.check_at(limits, user, channel, Instant::now())
+    }
+
+    fn check_at(&self, limits: &Limits, user: &str, channel: &str, now: Instant) -> Decision {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        let user_key = format!("user:{}", irc_lowercase(user));
+        let chan_key = format!("channel:{}", irc_lowercase(channel));
+
+        if state.users.len() + state.channels.len() > PRUNE_THRESHOLD {
+            state.users.retain(|_, b| !b.is_full(&limits.user, now));
+            state.channels.retain(|_, b| !b.is_full(&limits.channel, now));
+
+            let State { users, channels, notified } = state;
+            notified.retain(|k| users.contains_key(k) || channels.contains_key(k));
+        }
+
+        let mut user_bucket = if limits.user.enabled() {
+            let b = state.users
+                .entry(user_key.clone())
+                .or_insert_with(|| Bucket::full(&limits.user, now));
+            b.refill(&limits.user, now);
+            Some(b)
+        } else {
+            None
+        };
+
+        if user_bucket.as_ref().is_some_and(|b| b.tokens < 1.0) {
+            let notify = state.notified.insert(user_key);
+            return Decision::UserThrottled { notify };
+        }
+
+        let chan_bucket = if limits.channel.enabled() {
+            let b = state.channels
+                .entry(chan_key.clone())
+                .or_insert_with(|| Bucket::full(&limits.channel, now));
+            b.refill(&limits.channel, now);
+            Some(b)
+        } else {
+            None
+        };
+
+        match chan_bucket {
+            Some(b) if b.tokens < 1.0 => {
+                let notify = state.notified.insert(chan_key);
+                return Decision::ChannelThrottled { notify };
+            },
+            Some(b) => b.tokens -= 1.0,
+            None => {},
+        }
+
+        if let Some(b) = user_bucket.as_mut() {
+            b.tokens -= 1.0;
+        }
+
+        state.notified.remove(&user_key);
+        state.notified.remove(&chan_key);
+
+        Decision::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Decision::*;
+
+    fn limits(user: (u32, u64), channel: (u32, u64)) -> Limits {
+        Limits {
+            user: Limit { burst: user.0, refill: Duration::from_secs(user.1) },
+            channel: Limit { burst: channel.0, refill: Duration::from_secs(channel.1) },
+        }
+    }
+
+    #[test]
+    fn test_disabled() {
+        let rl = RateLimiter::new();
+        let now = Instant::now();
+
+        for l in &[limits((0, 0), (0, 0)), limits((0, 10), (0, 10)), limits((5, 0), (5, 0))] {
+            for _ in 0..100 {
+                assert_eq!(Allow, rl.check_at(l, "nick", "#chan", now));
+            }
+        }
+    }
+
+    #[test]
+    fn test_user_limit() {
+        let rl = RateLimiter::new();
+        let l = limits((3, 10), (0, 0));
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(Allow, rl.check_at(&l, "nick", "#chan", now));
+        }
+        assert_eq!(UserThrottled { notify: true }, rl.check_at(&l, "nick", "#chan", now));
+        assert_eq!(UserThrottled { notify: false }, rl.check_at(&l, "NICK", "#chan", now));
+
+        // other users are unaffected
+        assert_eq!(Allow, rl.check_at(&l, "other", "#chan", now));
+
+        // a token is regained after the refill time
+        let now = now + Duration::from_secs(9);
+        assert_eq!(UserThrottled { notify: false }, rl.check_at(&l, "nick", "#chan", now));
+        let now = now + Duration::from_secs(1);
+        assert_eq!(Allow, rl.check_at(&l, "nick", "#other", now));
+        assert_eq!(UserThrottled { notify: true }, rl.check_at(&l, "nick", "#chan", now));
+
+        // the bucket doesn't fill beyond the burst size
+        let now = now + Duration::from_secs(1000);
+        for _ in 0..3 {
+            assert_eq!(Allow, rl.check_at(&l, "nick", "#chan", now));
+        }
+        assert_eq!(UserThrottled { notify: true }, rl.check_at(&l, "nick", "#chan", now));
+    }
+
+    #[test]
+    fn test_channel_limit() {
+        let rl = RateLimiter::new();
+        let l = limits((2, 10), (3, 5));
+        let now = Instant::now();
+
+        assert_eq!(Allow, rl.check_at(&l, "a", "#chan", now));
+        assert_eq!(Allow, rl.check_at(&l, "b", "#chan", now));
+        assert_eq!(Allow, rl.check_at(&l, "c", "#Chan", now));
+        assert_eq!(ChannelThrottled { notify: true }, rl.check_at(&l, "a", "#chan", now));
+        assert_eq!(ChannelThrottled { notify: false }, rl.check_at(&l, "d", "#chan", now));
+        assert_eq!(Allow, rl.check_at(&l, "a", "#other", now));
+
+        // user "a" has used both their tokens, refused lookups don't count
+        assert_eq!(UserThrottled { notify: true }, rl.check_at(&l, "a", "#other", now));
+
+        let now = now + Duration::from_secs(5);
+        assert_eq!(Allow, rl.check_at(&l, "d", "#chan", now));
+        assert_eq!(ChannelThrottled { notify: true }, rl.check_at(&l, "d", "#chan", now));
+    }
+
+    #[test]
+    fn test_shared_state() {
+        let rl = RateLimiter::new();
+        let clone = rl.clone();
+        let l = limits((1, 10), (0, 0));
+        let now = Instant::now();
+
+        assert_eq!(Allow, rl.check_at(&l, "nick", "#chan", now));
+        assert_eq!(UserThrottled { notify: true }, clone.check_at(&l, "nick", "#chan", now));
+    }
+
+    #[test]
+    fn test_prune() {
+        let rl = RateLimiter::new();
+        let l = limits((1, 10), (0, 0));
+        let now = Instant::now();
+
+        for n in 0..=PRUNE_THRESHOLD {
+            rl.check_at(&l, &n.to_string(), "#chan", now);
+            rl.check_at(&l, &n.to_string(), "#chan", now);
+        }
+        assert_eq!(PRUNE_THRESHOLD + 1, rl.state.lock().unwrap().notified.len());
+
+        let now = now + Duration::from_secs(10);
+        rl.check_at(&l, "nick", "#chan", now);
+        assert_eq!(1, rl.state.lock().unwrap().users.len());
+        assert!(rl.state.lock().unwrap().notified.is_empty());
+    }
+}
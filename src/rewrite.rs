@@ -0,0 +1,239 @@
+/*
+ * Title rewriting
+ *
+ */
+use failure::{Error, bail};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::{
+    config::ConfRegex,
+    filter::host_matches,
+};
+
+/// A title rewriting rule, applied to titles of URLs from matching domains
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct Rule {
+    /// host names, either exact, or matching any subdomain if given as
+    /// `*.example.com`, the rule applies to all domains if empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub domains: Vec<String>,
+    /// regular expression to search for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regex: Option<ConfRegex>,
+    /// replacement for matches of `regex`, which may refer to capture groups
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub replace: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_suffix: Option<String>,
+}
+
+impl Rule {
+    fn applies(&self, hosts: &[&str]) -> bool {
+        self.domains.is_empty() || self.domains
+            .iter()
+            .any(|d| hosts.iter().any(|h| host_matches(d, h)))
+    }
+
+    fn apply(&self, title: &str) -> String {
+        let mut title = match &self.regex {
+            Some(r) => r.0.replace_all(title, self.replace.as_str()).into_owned(),
+            None => title.to_string(),
+        };
+
+        if let Some(p) = &self.strip_prefix {
+            if let Some(t) = title.strip_prefix(p.as_str()) {
+                title = t.to_string();
+            }
+        }
+
+        if let Some(s) = &self.strip_suffix {
+            if let Some(t) = title.strip_suffix(s.as_str()) {
+                title = t.to_string();
+            }
+        }
+
+        title
+    }
+}
+
+fn builtin(domains: &[&str], regex: Option<&str>, prefix: Option<&str>, suffix: Option<&str>) -> Rule {
+    Rule {
+        domains: domains.iter().map(|d| d.to_string()).collect(),
+        regex: regex.map(|r| ConfRegex(Regex::new(r).unwrap())),
+        replace: String::new(),
+        strip_prefix: prefix.map(str::to_string),
+        strip_suffix: suffix.map(str::to_string),
+    }
+}
+
+lazy_static! {
+    /// rules for common sites which add their name to titles
+    static ref BUILTIN: Vec<Rule> = vec![
+        builtin(&["youtube.com", "*.youtube.com", "youtu.be"],
+            None, None, Some(" - YouTube")),
+        builtin(&["github.com"],
+            None, Some("GitHub - "), Some(" · GitHub")),
+        builtin(&["theguardian.com", "*.theguardian.com"],
+            Some(r"( \| [^|]+)*\s*\| The Guardian$"), None, None),
+        builtin(&["*.wikipedia.org"],
+            None, None, Some(" - Wikipedia")),
+        builtin(&["reddit.com", "*.reddit.com"],
+            Some(r"\s*: r/\w+$"), None, None),
+        builtin(&["stackoverflow.com"],
+            None, None, Some(" - Stack Overflow")),
+        builtin(&["bbc.co.uk", "*.bbc.co.uk", "bbc.com", "*.bbc.com"],
+            None, Some("BBC - "), Some(" - BBC News")),
+    ];
+}
+
+/// Rules for rewriting titles before they are used in responses
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TitleRewrite {
+    /// apply the built-in rules for common sites, before any others
+    pub builtin: bool,
+    /// replace runs of whitespace with a single space
+    pub collapse_whitespace: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+}
+
+impl Default for TitleRewrite {
+    fn default() -> Self {
+        Self {
+            builtin: true,
+            collapse_whitespace: true,
+            rules: vec![],
+        }
+    }
+}
+
+impl TitleRewrite {
+    /// Rewrite a title, for a URL with the given host names, i.e. as posted,
+    /// and after any redirects
+    ///
+    /// The original title is kept if rewriting leaves nothing.
+    pub fn apply(&self, title: &str, hosts: &[&str]) -> String {
+        let builtin = if self.builtin { &BUILTIN[..] } else { &[] };
+
+        let mut rewritten = builtin
+            .iter()
+            .chain(&self.rules)
+            .filter(|r| r.applies(hosts))
+            .fold(title.to_string(), |t, r| r.apply(&t));
+
+        if self.collapse_whitespace {
+            rewritten = rewritten.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+
+        let rewritten = rewritten.trim();
+
+        if rewritten.is_empty() {
+            title.to_string()
+        } else {
+            rewritten.to_string()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        for (n, rule) in self.rules.iter().enumerate() {
+            if rule.regex.is_none() && rule.strip_prefix.is_none() && rule.strip_suffix.is_none() {
+                bail!("rule {}: no regex, strip_prefix, or strip_suffix given", n + 1);
+            }
+            if rule.regex.is_none() && !rule.replace.is_empty() {
+                bail!("rule {}: replace given without regex", n + 1);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(r: &TitleRewrite, title: &str, host: &str) -> String {
+        r.apply(title, &[host])
+    }
+
+    #[test]
+    fn test_builtin() {
+        let r = TitleRewrite::default();
+
+        for (title, host, expected) in &[
+            ("Foo - YouTube", "www.youtube.com", "Foo"),
+            ("Foo - YouTube", "youtu.be", "Foo"),
+            ("Foo - YouTube", "example.com", "Foo - YouTube"),
+            ("GitHub - owner/repo: a thing", "github.com", "owner/repo: a thing"),
+            ("Bar | Politics | The Guardian", "www.theguardian.com", "Bar"),
+            ("Bar | The Guardian", "theguardian.com", "Bar"),
+            ("Rust (programming language) - Wikipedia", "en.wikipedia.org",
+                "Rust (programming language)"),
+            ("Post title : r/rust", "old.reddit.com", "Post title"),
+            ("Headline - BBC News", "www.bbc.co.uk", "Headline"),
+            // nothing left, so the title is kept
+            (" - YouTube", "youtube.com", " - YouTube"),
+        ] {
+            assert_eq!(*expected, rewrite(&r, title, host));
+        }
+
+        let r = TitleRewrite { builtin: false, ..TitleRewrite::default() };
+        assert_eq!("Foo - YouTube", rewrite(&r, "Foo - YouTube", "youtube.com"));
+    }
+
+    #[test]
+    fn test_rules() {
+        let r: TitleRewrite = toml::de::from_str(r#"
+            builtin = false
+
+            [[rules]]
+            domains = ["example.com", "*.example.org"]
+            regex = "^(\\w+): (.*)$"
+            replace = "$2 ($1)"
+
+            [[rules]]
+            domains = ["example.com"]
+            strip_prefix = "Example | "
+
+            [[rules]]
+            strip_suffix = "!"
+        "#).unwrap();
+        r.validate().unwrap();
+
+        assert_eq!("b (a)", rewrite(&r, "a: b", "example.com"));
+        assert_eq!("b! (a)", rewrite(&r, "a: b!", "example.com"));
+        assert_eq!("b (a)", rewrite(&r, "a: b", "www.example.org"));
+        assert_eq!("a: b", rewrite(&r, "a: b!", "example.net"));
+        assert_eq!("a: x", rewrite(&r, "Example | a: x", "example.com"));
+        assert_eq!("b", rewrite(&r, "Example |  b", "example.com"));
+        assert_eq!("b (a)", r.apply("a: b", &["example.net", "example.com"]));
+    }
+
+    #[test]
+    fn test_collapse_whitespace() {
+        let mut r = TitleRewrite::default();
+        assert_eq!("a b c", rewrite(&r, " a  b\tc ", "example.com"));
+
+        r.collapse_whitespace = false;
+        assert_eq!("a  b\tc", rewrite(&r, " a  b\tc ", "example.com"));
+    }
+
+    #[test]
+    fn test_validate() {
+        for rules in &[
+            "[[rules]]\ndomains = [\"example.com\"]",
+            "[[rules]]\nreplace = \"x\"\nstrip_suffix = \"y\"",
+        ] {
+            let r: TitleRewrite = toml::de::from_str(rules).unwrap();
+            assert!(r.validate().is_err());
+        }
+
+        assert!(toml::de::from_str::<TitleRewrite>("[[rules]]\nregex = \"(\"").is_err());
+    }
+}
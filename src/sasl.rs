@@ -0,0 +1,163 @@
+/*
+ * SASL authentication
+ *
+ */
+use irc::client::{data::Config as IrcConfig, prelude::*};
+use failure::{Error, bail};
+use serde_derive::{Serialize, Deserialize};
+
+/// maximum length of base64 data in a single AUTHENTICATE message
+const CHUNK_MAX: usize = 400;
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mechanism {
+    /// don't authenticate with SASL
+    #[default]
+    None,
+    /// authenticate with a username and password
+    Plain,
+    /// authenticate with the client TLS certificate
+    External,
+}
+
+impl Mechanism {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mechanism::None => "",
+            Mechanism::Plain => "PLAIN",
+            Mechanism::External => "EXTERNAL",
+        }
+    }
+}
+
+/// SASL authentication configuration
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct Sasl {
+    pub mechanism: Mechanism,
+    /// account name for PLAIN, if empty, the nickname is used
+    pub username: String,
+    /// password for PLAIN
+    pub password: String,
+}
+
+impl Sasl {
+    pub fn enabled(&self) -> bool {
+        self.mechanism != Mechanism::None
+    }
+
+    /// Start authenticating, once the `sasl` capability has been enabled
+    pub fn start(&self) -> Command {
+        Command::AUTHENTICATE(self.mechanism.name().to_string())
+    }
+
+    /// Answer an AUTHENTICATE challenge from the server
+    pub fn respond(&self, nick: &str, challenge: &str) -> Result<Vec<Command>, Error> {
+        if challenge != "+" {
+            bail!("unexpected SASL challenge `{}`", challenge);
+        }
+
+        let payload = match self.mechanism {
+            Mechanism::Plain => {
+                let user = if self.username.is_empty() { nick } else { &self.username };
+                base64::encode(format!("\0{}\0{}", user, self.password))
+            },
+            Mechanism::External => String::new(),
+            Mechanism::None => bail!("SASL authentication isn't configured"),
+        };
+
+        // long payloads are split, and followed by an empty message if the
+        // last chunk is full
+        let mut chunks: Vec<Command> = payload.as_bytes()
+            .chunks(CHUNK_MAX)
+            .map(|c| Command::AUTHENTICATE(String::from_utf8_lossy(c).into_owned()))
+            .collect();
+
+        if payload.len() % CHUNK_MAX == 0 {
+            chunks.push(Command::AUTHENTICATE("+".to_string()));
+        }
+
+        Ok(chunks)
+    }
+
+    pub fn validate(&self, client: &IrcConfig) -> Result<(), Error> {
+        match self.mechanism {
+            Mechanism::Plain if self.password.is_empty() => {
+                bail!("a password is needed for PLAIN authentication")
+            },
+            Mechanism::External if client.client_cert_path().is_none() => {
+                bail!("`client_cert_path` is needed for EXTERNAL authentication")
+            },
+            Mechanism::External if !client.use_ssl() => {
+                bail!("`use_ssl` is needed for EXTERNAL authentication")
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn respond(sasl: &Sasl, challenge: &str) -> Vec<String> {
+        sasl.respond("nick", challenge)
+            .unwrap()
+            .iter()
+            .map(|c| String::from(c).trim_end().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_respond() {
+        let mut sasl = Sasl {
+            mechanism: Mechanism::Plain,
+            username: "user".into(),
+            password: "pass".into(),
+        };
+        assert_eq!("AUTHENTICATE PLAIN", String::from(&sasl.start()).trim_end());
+        assert_eq!(vec!["AUTHENTICATE AHVzZXIAcGFzcw=="], respond(&sasl, "+"));
+        assert!(sasl.respond("nick", "abc").is_err());
+
+        sasl.username = String::new();
+        assert_eq!(vec!["AUTHENTICATE AG5pY2sAcGFzcw=="], respond(&sasl, "+"));
+
+        // "\0nick\0" and a password encode to exactly 400 bytes
+        sasl.password = "p".repeat(294);
+        let lines = respond(&sasl, "+");
+        assert_eq!(2, lines.len());
+        assert_eq!(13 + 400, lines[0].len());
+        assert_eq!("AUTHENTICATE +", lines[1]);
+
+        sasl.password = "p".repeat(300);
+        let lines = respond(&sasl, "+");
+        assert_eq!(2, lines.len());
+        assert_ne!("AUTHENTICATE +", lines[1]);
+
+        sasl.mechanism = Mechanism::External;
+        assert_eq!(vec!["AUTHENTICATE +"], respond(&sasl, "+"));
+
+        sasl.mechanism = Mechanism::None;
+        assert!(sasl.respond("nick", "+").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut client = IrcConfig::default();
+        let mut sasl = Sasl::default();
+        assert!(sasl.validate(&client).is_ok());
+
+        sasl.mechanism = Mechanism::Plain;
+        assert!(sasl.validate(&client).is_err());
+        sasl.password = "pass".into();
+        assert!(sasl.validate(&client).is_ok());
+
+        sasl.mechanism = Mechanism::External;
+        assert!(sasl.validate(&client).is_err());
+        client.client_cert_path = Some("cert.p12".into());
+        assert!(sasl.validate(&client).is_err());
+        client.use_ssl = Some(true);
+        assert!(sasl.validate(&client).is_ok());
+    }
+}
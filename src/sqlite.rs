@@ -1,17 +1,38 @@
-use rusqlite::Connection;
-use failure::{Error, SyncFailure};
-use std::path::Path;
+use rusqlite::{Connection, ErrorCode, TransactionBehavior};
+use failure::{Error, SyncFailure, bail, format_err};
+use std::{path::Path, sync::Mutex, thread, time::Duration};
 use serde_rusqlite::{from_rows, to_params_named};
-use chrono::Utc;
-use serde_derive::{Serialize, Deserialize};
+use chrono::{DateTime, NaiveDateTime, Utc};
 
+use crate::history::{
+    HistoryStore, NewLogEntry, LogEntry, NewErrorEntry, FailureCounts, HostFailures, PrevPost,
+    TIME_FORMAT, like_pattern,
+};
+
+/// how long to wait for another connection to finish writing to the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// how many times to retry writing if the database is still busy after that
+const BUSY_RETRIES: u32 = 3;
+/// how long to wait before retrying, multiplied by the number of attempts
+const BUSY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// A history store in an SQLite database, which may be opened by several
+/// threads and processes at once
 pub struct Database {
-    db: Connection,
+    db: Mutex<Connection>,
 }
 
 impl Database {
     pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
         let db = Connection::open(path)?;
+        db.busy_timeout(BUSY_TIMEOUT)?;
+
+        // let readers continue while another connection writes
+        retry(|| {
+            db.query_row("PRAGMA journal_mode = WAL", &[], |row| row.get::<_, String>(0))?;
+            Ok(())
+        })?;
+
         Self::from_connection(db)
     }
 
@@ -20,67 +41,844 @@ impl Database {
         Self::from_connection(db)
     }
 
-    fn from_connection(db: Connection) -> Result<Self, Error> {
-        db.execute("CREATE TABLE IF NOT EXISTS posts (
+    fn from_connection(mut db: Connection) -> Result<Self, Error> {
+        retry(|| migrate(&mut db))?;
+        Ok(Self { db: Mutex::new(db) })
+    }
+
+    /// get the version of the schema, i.e. the number of migrations applied
+    pub fn schema_version(&self) -> Result<usize, Error> {
+        schema_version(&self.db.lock().unwrap())
+    }
+}
+
+impl HistoryStore for Database {
+    fn add_log(&self, entry: &NewLogEntry) -> Result<(), Error> {
+        let db = self.db.lock().unwrap();
+        retry(|| insert_log(&db, entry))
+    }
+
+    fn add_error(&self, entry: &NewErrorEntry) -> Result<(), Error> {
+        let failed_at = Utc::now().timestamp();
+        let params = to_params_named(entry).map_err(SyncFailure::new)?;
+        let mut params = params.to_slice();
+        params.push((":failed_at", &failed_at));
+
+        let db = self.db.lock().unwrap();
+        retry(|| {
+            db.execute_named("
+                INSERT INTO errors ( url,  error_info,  host,  kind,  status,  host_failure,
+                                     network,  channel,  failed_at)
+                VALUES             (:url, :error_info, :host, :kind, :status, :host_failure,
+                                    :network, :channel, :failed_at)",
+                &params
+            )?;
+            Ok(())
+        })
+    }
+
+    fn failure_counts(&self, url: &str, host: &str, since: DateTime<Utc>)
+        -> Result<FailureCounts, Error>
+    {
+        let since = since.timestamp();
+        let db = self.db.lock().unwrap();
+        let mut st = db.prepare("
+            SELECT
+                (SELECT COUNT(*) FROM errors
+                 WHERE url = :url AND failed_at >= :since) AS url,
+                (SELECT COUNT(*) FROM errors
+                 WHERE host = :host AND host_failure AND failed_at >= :since) AS host
+        ")?;
+        let rows = st.query_named(&[(":url", &url), (":host", &host), (":since", &since)])?;
+        let mut rows = from_rows::<FailureCounts>(rows);
+
+        Ok(rows.next().unwrap_or_default())
+    }
+
+    fn failing_hosts(&self, since: DateTime<Utc>, limit: u32)
+        -> Result<Vec<HostFailures>, Error>
+    {
+        let since = since.timestamp();
+        let db = self.db.lock().unwrap();
+        let mut st = db.prepare("
+            SELECT host, COUNT(*) AS count, (
+                SELECT kind FROM errors AS e
+                WHERE e.host = errors.host AND failed_at >= :since
+                ORDER BY id DESC LIMIT 1
+            ) AS last_kind
+            FROM errors
+            WHERE host IS NOT NULL AND failed_at >= :since
+            GROUP BY host
+            ORDER BY count DESC, host
+            LIMIT :limit
+        ")?;
+        let rows = st.query_named(&[(":since", &since), (":limit", &limit)])?;
+
+        Ok(from_rows::<HostFailures>(rows).collect())
+    }
+
+    fn check_prepost(
+        &self,
+        key: &str,
+        url: &str,
+        network: Option<&str>,
+        channel: Option<&str>,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Option<PrevPost>, Error> {
+        let db = self.db.lock().unwrap();
+        prepost(&db, key, url, network, channel, since)
+    }
+
+    fn check_and_add_log(
+        &self,
+        entry: &NewLogEntry,
+        network: Option<&str>,
+        channel: Option<&str>,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Option<PrevPost>, Error> {
+        let mut db = self.db.lock().unwrap();
+
+        // take the write lock before checking, so that other processes
+        // can't post the URL in between
+        retry(|| {
+            let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let previous = prepost(&tx, entry.url_key, entry.url, network, channel, since)?;
+            insert_log(&tx, entry)?;
+            tx.commit()?;
+
+            Ok(previous)
+        })
+    }
+
+    fn prune(&self, before: DateTime<Utc>, archive: bool) -> Result<usize, Error> {
+        let before = before.timestamp();
+        let mut db = self.db.lock().unwrap();
+
+        retry(|| {
+            let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            if archive {
+                tx.execute_named("
+                    INSERT OR REPLACE INTO posts_archive
+                        (id, title, url, user, channel, network, time_created, url_key)
+                    SELECT id, title, url, user, channel, network, time_created, url_key
+                    FROM posts
+                    WHERE time_created < :before",
+                    &[(":before", &before)]
+                )?;
+            }
+
+            let removed = tx.execute_named(
+                "DELETE FROM posts WHERE time_created < :before",
+                &[(":before", &before)]
+            )?;
+            tx.execute_named(
+                "DELETE FROM errors WHERE failed_at < :before",
+                &[(":before", &before)]
+            )?;
+            tx.commit()?;
+
+            Ok(removed)
+        })
+    }
+
+    fn search_posts(&self, network: &str, channel: &str, text: &str, limit: u32)
+        -> Result<Vec<LogEntry>, Error>
+    {
+        let db = self.db.lock().unwrap();
+        let mut st = db.prepare("
+            SELECT title, url, user, channel, time_created
+            FROM posts
+            WHERE (network IS NULL OR network = :network) AND channel = :channel
+            AND (title LIKE :pattern ESCAPE '\\' OR url LIKE :pattern ESCAPE '\\')
+            ORDER BY id DESC
+            LIMIT :limit
+        ")?;
+        let rows = st.query_named(&[
+            (":network", &network),
+            (":channel", &channel),
+            (":pattern", &like_pattern(text)),
+            (":limit", &limit),
+        ])?;
+
+        Ok(from_rows::<LogEntry>(rows).collect())
+    }
+
+    fn prune_errors(&self, before: DateTime<Utc>) -> Result<usize, Error> {
+        let before = before.timestamp();
+        let db = self.db.lock().unwrap();
+
+        retry(|| {
+            let removed = db.execute_named(
+                "DELETE FROM errors WHERE failed_at < :before",
+                &[(":before", &before)]
+            )?;
+            Ok(removed)
+        })
+    }
+
+    fn recent_posts(&self, network: &str, channel: &str, limit: u32)
+        -> Result<Vec<LogEntry>, Error>
+    {
+        let db = self.db.lock().unwrap();
+        let mut st = db.prepare("
+            SELECT title, url, user, channel, time_created
+            FROM posts
+            WHERE (network IS NULL OR network = :network) AND channel = :channel
+            ORDER BY id DESC
+            LIMIT :limit
+        ")?;
+        let rows = st.query_named(&[
+            (":network", &network),
+            (":channel", &channel),
+            (":limit", &limit),
+        ])?;
+
+        Ok(from_rows::<LogEntry>(rows).collect())
+    }
+}
+
+/// record a post of a URL
+fn insert_log(db: &Connection, entry: &NewLogEntry) -> Result<(), Error> {
+    let time_created = Utc::now().timestamp();
+    let params = to_params_named(entry).map_err(SyncFailure::new)?;
+    let mut params = params.to_slice();
+    params.push((":time_created", &time_created));
+
+    db.execute_named("
+        INSERT INTO posts ( title,  url,  user,  channel,  network,  time_created,
+                            url_key)
+        VALUES            (:title, :url, :user, :channel, :network, :time_created,
+                           :url_key)",
+        &params
+    )?;
+
+    Ok(())
+}
+
+/// find earlier posts of a URL, see `HistoryStore::check_prepost`
+fn prepost(
+    db: &Connection,
+    key: &str,
+    url: &str,
+    network: Option<&str>,
+    channel: Option<&str>,
+    since: Option<DateTime<Utc>>,
+) -> Result<Option<PrevPost>, Error> {
+    let since = since.map(|t| t.timestamp());
+    let mut st = db.prepare("
+        WITH matches AS (
+            SELECT id, user, time_created, channel, network
+            FROM posts
+            WHERE (url_key = :key OR (url_key IS NULL AND url = :url))
+            AND (:network IS NULL OR network IS NULL OR network = :network)
+            AND (:channel IS NULL OR channel = :channel)
+            AND (:since IS NULL OR time_created >= :since)
+        )
+        SELECT
+            (SELECT COUNT(*) FROM matches) AS count,
+            first.user AS user,
+            first.time_created AS time_created,
+            first.channel AS channel,
+            first.network AS network,
+            last.user AS last_user,
+            last.time_created AS last_time_created,
+            last.channel AS last_channel,
+            last.network AS last_network
+        FROM matches AS first, matches AS last
+        WHERE first.id = (SELECT MIN(id) FROM matches)
+        AND last.id = (SELECT MAX(id) FROM matches)
+    ")?;
+    let rows = st.query_named(&[
+        (":key", &key),
+        (":url", &url),
+        (":network", &network),
+        (":channel", &channel),
+        (":since", &since),
+    ])?;
+    let mut rows = from_rows::<PrevPost>(rows);
+
+    Ok(rows.next())
+}
+
+/// A step in upgrading the schema of the database, from the previous version
+type Migration = fn(&Connection) -> Result<(), Error>;
+
+/// Schema migrations, in order, where the `user_version` of a database is the
+/// number which have been applied to it
+///
+/// Migrations must not be changed once released, only added to.
+const MIGRATIONS: [Migration; 5] = [
+    migrate_url_key,
+    migrate_archive,
+    migrate_errors,
+    migrate_timestamps,
+    migrate_network,
+];
+
+fn schema_version(db: &Connection) -> Result<usize, Error> {
+    let version: i64 = db.query_row("PRAGMA user_version", &[], |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+/// run an operation on the database, retrying it if the database is still
+/// busy after the timeout, e.g. while another process is writing a lot to it
+fn retry<T>(mut op: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+    let mut attempts = 0;
+
+    loop {
+        match op() {
+            Err(ref e) if is_busy(e) && attempts < BUSY_RETRIES => {
+                attempts += 1;
+                thread::sleep(BUSY_BACKOFF * attempts);
+            },
+            result => return result,
+        }
+    }
+}
+
+fn is_busy(err: &Error) -> bool {
+    match err.downcast_ref::<rusqlite::Error>() {
+        Some(rusqlite::Error::SqliteFailure(e, _)) => {
+            matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+        },
+        _ => false,
+    }
+}
+
+/// bring the schema up to date, applying each migration in its own transaction
+///
+/// Each migration takes the write lock before checking the version, so that
+/// if several processes open the database at once, only one applies it.
+fn migrate(db: &mut Connection) -> Result<(), Error> {
+    let version = schema_version(db)?;
+
+    if version > MIGRATIONS.len() {
+        bail!("database schema version {} is newer than supported ({})",
+            version, MIGRATIONS.len());
+    }
+
+    if version == 0 {
+        let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if schema_version(&tx)? == 0 {
+            create_unversioned(&tx)?;
+        }
+        tx.commit()?;
+    }
+
+    for (n, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        // another process may have applied it since the version was checked
+        if schema_version(&tx)? > n {
+            continue;
+        }
+
+        migration(&tx).map_err(|e| format_err!("database migration {}: {}", n + 1, e))?;
+        tx.execute(&format!("PRAGMA user_version = {}", n + 1), &[])?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+/// create the schema from before it was versioned, as every earlier release
+/// did, unless the database already has it
+fn create_unversioned(db: &Connection) -> Result<(), Error> {
+    db.execute_batch("
+        CREATE TABLE IF NOT EXISTS posts (
             id              INTEGER PRIMARY KEY,
             title           TEXT NOT NULL,
             url             TEXT NOT NULL,
             user            TEXT NOT NULL,
             channel         TEXT NOT NULL,
             time_created    TEXT NOT NULL
-            )",
-            &[]
-        )?;
-        db.execute("CREATE TABLE IF NOT EXISTS errors (
+        );
+        CREATE TABLE IF NOT EXISTS errors (
             id              INTEGER PRIMARY KEY,
             url             TEXT NOT NULL,
             error_info      TEXT NOT NULL
-            )",
-            &[]
-        )?;
+        );
+    ")?;
 
-        Ok(Self { db })
-    }
+    Ok(())
+}
 
-    pub fn add_log(&self, entry: &NewLogEntry) -> Result<(), Error> {
-        let time_created = Utc::now().format("%a %b %-d %H:%M:%S %-Y").to_string();
-        let params = to_params_named(entry).map_err(SyncFailure::new)?;
-        let mut params = params.to_slice();
-        params.push((":time_created", &time_created));
+/// 1: key posts by their canonical URL, see `canonical::Canonical::key`
+///
+/// Posts from before then have no key, and are matched by URL.
+fn migrate_url_key(db: &Connection) -> Result<(), Error> {
+    db.execute_batch("ALTER TABLE posts ADD COLUMN url_key TEXT;")?;
+    Ok(())
+}
 
-        self.db.execute_named("
-            INSERT INTO posts ( title,  url,  user,  channel,  time_created)
-            VALUES            (:title, :url, :user, :channel, :time_created)",
-            &params
-        )?;
+/// 2: add a table to move old posts to, rather than deleting them
+fn migrate_archive(db: &Connection) -> Result<(), Error> {
+    db.execute_batch("
+        CREATE TABLE posts_archive (
+            id              INTEGER PRIMARY KEY,
+            title           TEXT NOT NULL,
+            url             TEXT NOT NULL,
+            user            TEXT NOT NULL,
+            channel         TEXT NOT NULL,
+            time_created    TEXT NOT NULL,
+            url_key         TEXT
+        );
+    ")?;
 
-        Ok(())
-    }
+    Ok(())
+}
 
-    pub fn check_prepost(&self, url: &str) -> Result<Option<PrevPost>, Error> {
-        let mut st = self.db.prepare("
-            SELECT user, time_created, channel
-            FROM posts
-            WHERE url LIKE :url
-        ")?;
-        let rows = st.query_named(&[(":url", &url)])?;
-        let mut rows = from_rows::<PrevPost>(rows);
+/// 3: record the details of lookup failures, in the table which earlier
+/// releases created, but didn't use
+fn migrate_errors(db: &Connection) -> Result<(), Error> {
+    db.execute_batch("
+        ALTER TABLE errors ADD COLUMN host TEXT;
+        ALTER TABLE errors ADD COLUMN kind TEXT;
+        ALTER TABLE errors ADD COLUMN status INTEGER;
+        ALTER TABLE errors ADD COLUMN host_failure INTEGER;
+        ALTER TABLE errors ADD COLUMN network TEXT;
+        ALTER TABLE errors ADD COLUMN channel TEXT;
+        ALTER TABLE errors ADD COLUMN failed_at INTEGER;
+        CREATE INDEX errors_url ON errors (url, failed_at);
+        CREATE INDEX errors_host ON errors (host, failed_at);
+    ")?;
+
+    Ok(())
+}
 
-        Ok(rows.next())
+/// 4: store the time of posts as a UTC Unix timestamp, which sorts and
+/// compares, rather than as text
+fn migrate_timestamps(db: &Connection) -> Result<(), Error> {
+    for table in &["posts", "posts_archive"] {
+        db.execute_batch(&format!("
+            CREATE TABLE {}_new (
+                id              INTEGER PRIMARY KEY,
+                title           TEXT NOT NULL,
+                url             TEXT NOT NULL,
+                user            TEXT NOT NULL,
+                channel         TEXT NOT NULL,
+                time_created    INTEGER NOT NULL,
+                url_key         TEXT
+            );", table))?;
+
+        let posts: Vec<(i64, String)> = db
+            .prepare(&format!("SELECT id, time_created FROM {}", table))?
+            .query_map(&[], |row| (row.get(0), row.get(1)))?
+            .collect::<Result<_, _>>()?;
+
+        for (id, time) in posts {
+            let time = match parse_time(&time) {
+                Some(t) => t.timestamp(),
+                None => bail!("{} {} has an invalid time `{}`", table, id, time),
+            };
+            db.execute(&format!("
+                INSERT INTO {0}_new (id, title, url, user, channel, time_created, url_key)
+                SELECT id, title, url, user, channel, ?1, url_key FROM {0} WHERE id = ?2",
+                table), &[&time, &id]
+            )?;
+        }
+
+        db.execute_batch(&format!("
+            DROP TABLE {0};
+            ALTER TABLE {0}_new RENAME TO {0};", table))?;
     }
+
+    db.execute_batch("
+        CREATE INDEX posts_url_key ON posts (url_key);
+        CREATE INDEX posts_url ON posts (url);
+        CREATE INDEX posts_time_created ON posts (time_created);
+    ")?;
+
+    Ok(())
 }
 
-#[derive(Debug, Serialize)]
-pub struct NewLogEntry<'a> {
-    pub title: &'a str,
-    pub url: &'a str,
-    pub user: &'a str,
-    pub channel: &'a str,
+/// 5: record the network of posts, so that networks can share a database
+///
+/// Posts from before then have no network, and are treated as being from any.
+fn migrate_network(db: &Connection) -> Result<(), Error> {
+    db.execute_batch("
+        ALTER TABLE posts ADD COLUMN network TEXT;
+        ALTER TABLE posts_archive ADD COLUMN network TEXT;
+        CREATE INDEX posts_channel ON posts (network, channel);
+    ")?;
+
+    Ok(())
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(time, TIME_FORMAT)
+        .ok()
+        .map(|t| DateTime::from_utc(t, Utc))
 }
 
-#[derive(Debug, Default, Deserialize)]
-pub struct PrevPost {
-    pub user: String,
-    pub time_created: String,
-    pub channel: String
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// open a database created from a fixture, in a temporary file
+    fn open_fixture(sql: &str) -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        let fixture = std::fs::read_to_string(format!("test/db/{}", sql)).unwrap();
+        Connection::open(&path).unwrap().execute_batch(&fixture).unwrap();
+        (dir, path)
+    }
+
+    fn columns(db: &Database, table: &str) -> Vec<(String, String)> {
+        db.db.lock().unwrap()
+            .prepare(&format!("PRAGMA table_info({})", table)).unwrap()
+            .query_map(&[], |row| (row.get(1), row.get(2))).unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_migrate_unversioned() {
+        let (_dir, path) = open_fixture("history-unversioned.sql");
+        let db = Database::open(&path).unwrap();
+        assert_eq!(MIGRATIONS.len(), db.schema_version().unwrap());
+        assert!(columns(&db, "posts").contains(&("time_created".into(), "INTEGER".into())));
+
+        let p = db.check_prepost("example.com/a", "http://example.com/a", None, None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!((2, "alice", "carol"), (p.count, p.user.as_str(), p.last_user.as_str()));
+        assert_eq!("2020-03-07T09:05:01+00:00", p.time().to_rfc3339());
+        assert_eq!("2021-01-04T00:00:00+00:00", p.last_time().to_rfc3339());
+
+        // the times of posts can be compared
+        let since = Utc.ymd(2020, 3, 8).and_hms(0, 0, 0);
+        let p = db.check_prepost("example.com/a", "http://example.com/a", None, None, Some(since))
+            .unwrap()
+            .unwrap();
+        assert_eq!((1, "carol"), (p.count, p.user.as_str()));
+        assert_eq!(1, db.prune(since, true).unwrap());
+
+        let entry = NewLogEntry {
+            title: "new",
+            url: "https://www.example.com/b?utm_source=x",
+            url_key: "example.com/b",
+            user: "dave",
+            channel: "#one",
+            network: "net",
+        };
+        db.add_log(&entry).unwrap();
+        drop(db);
+
+        // reopening an up to date database changes nothing
+        let db = Database::open(&path).unwrap();
+        assert_eq!(MIGRATIONS.len(), db.schema_version().unwrap());
+        let p = db.check_prepost("example.com/b", "http://example.com/b", None, None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!((2, "bob", "dave"), (p.count, p.user.as_str(), p.last_user.as_str()));
+        assert_eq!("dave", db.recent_posts("net", "#one", 1).unwrap()[0].user);
+    }
+
+    #[test]
+    fn test_migrate_steps() {
+        // each migration applies to the schema left by those before it
+        let mut db = Connection::open_in_memory().unwrap();
+        create_unversioned(&db).unwrap();
+        let table_info = |db: &Connection, table: &str| -> Vec<String> {
+            db.prepare(&format!("PRAGMA table_info({})", table)).unwrap()
+                .query_map(&[], |row| row.get(1)).unwrap()
+                .map(Result::unwrap)
+                .collect()
+        };
+        assert!(!table_info(&db, "posts").contains(&"url_key".into()));
+        assert!(table_info(&db, "posts_archive").is_empty());
+
+        for (n, migration) in MIGRATIONS.iter().enumerate() {
+            let tx = db.transaction().unwrap();
+            migration(&tx).unwrap_or_else(|e| panic!("migration {}: {}", n + 1, e));
+            tx.commit().unwrap();
+        }
+
+        for table in &["posts", "posts_archive"] {
+            let columns = table_info(&db, table);
+            assert!(columns.contains(&"url_key".into()));
+            assert!(columns.contains(&"network".into()));
+        }
+        assert!(table_info(&db, "errors").contains(&"failed_at".into()));
+
+        // the unversioned schema is left as it is once migrated
+        create_unversioned(&db).unwrap();
+        assert!(table_info(&db, "posts").contains(&"url_key".into()));
+    }
+
+    #[test]
+    fn test_migrate_invalid() {
+        // a failed migration is rolled back, keeping those applied before it
+        let (_dir, path) = open_fixture("history-unversioned.sql");
+        Connection::open(&path).unwrap()
+            .execute("UPDATE posts SET time_created = 'yesterday' WHERE id = 2", &[])
+            .unwrap();
+        assert!(Database::open(&path).is_err());
+
+        let db = Connection::open(&path).unwrap();
+        assert_eq!(3, schema_version(&db).unwrap());
+        let time: String = db.query_row("SELECT time_created FROM posts WHERE id = 1", &[], |r| r.get(0))
+            .unwrap();
+        assert_eq!("Sat Mar 7 09:05:01 2020", time);
+
+        // databases from later versions are not opened
+        db.execute(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1), &[]).unwrap();
+        assert!(Database::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_prune() {
+        let db = Database::open_in_memory().unwrap();
+        let post = |url| db.add_log(&NewLogEntry {
+            title: "t",
+            url,
+            url_key: url,
+            user: "a",
+            network: "net",
+            channel: "#c",
+        }).unwrap();
+        let count = |table| db.db.lock().unwrap()
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), &[], |r| r.get::<_, i64>(0))
+            .unwrap();
+
+        post("a");
+        post("b");
+        db.db.lock().unwrap()
+            .execute("UPDATE posts SET time_created = time_created - 86400 WHERE url = 'a'", &[])
+            .unwrap();
+
+        let an_hour_ago = Utc::now() - chrono::Duration::hours(1);
+        assert_eq!(1, db.prune(an_hour_ago, false).unwrap());
+        assert_eq!((1, 0), (count("posts"), count("posts_archive")));
+        assert!(db.check_prepost("a", "a", None, None, None).unwrap().is_none());
+
+        post("c");
+        db.db.lock().unwrap()
+            .execute("UPDATE posts SET time_created = time_created - 86400 WHERE url = 'c'", &[])
+            .unwrap();
+        assert_eq!(1, db.prune(an_hour_ago, true).unwrap());
+        assert_eq!((1, 1), (count("posts"), count("posts_archive")));
+        assert_eq!(0, db.prune(an_hour_ago, true).unwrap());
+    }
+
+    #[test]
+    fn test_prepost_count() {
+        let db = Database::open_in_memory().unwrap();
+        assert!(db.check_prepost("example.com", "http://example.com", None, None, None).unwrap().is_none());
+
+        for (user, channel) in &[("a", "#one"), ("b", "#two"), ("c", "#one"), ("d", "#two")] {
+            db.add_log(&NewLogEntry {
+                title: "t",
+                url: "http://example.com",
+                url_key: "example.com",
+                user,
+                channel,
+                network: "net",
+            }).unwrap();
+        }
+
+        let p = db.check_prepost("example.com", "http://example.com", None, None, None).unwrap().unwrap();
+        assert_eq!(4, p.count);
+        assert_eq!(("a", "#one"), (p.user.as_str(), p.channel.as_str()));
+        assert_eq!(("d", "#two"), (p.last_user.as_str(), p.last_channel.as_str()));
+
+        let elapsed = Utc::now() - p.time();
+        assert!(elapsed.num_seconds() >= 0 && elapsed.num_seconds() < 60);
+        assert!(p.last_time() >= p.time());
+
+        let p = db.check_prepost("example.com", "http://example.com", None, Some("#one"), None).unwrap().unwrap();
+        assert_eq!((2, "a", "c"), (p.count, p.user.as_str(), p.last_user.as_str()));
+
+        assert!(db.check_prepost("example.com", "http://example.com", None, Some("#three"), None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_prepost_network() {
+        let (_dir, path) = open_fixture("history-unversioned.sql");
+        let db = Database::open(&path).unwrap();
+        let post = |user, network| db.add_log(&NewLogEntry {
+            title: "t",
+            url: "http://example.com/a",
+            url_key: "example.com/a",
+            user,
+            channel: "#one",
+            network,
+        }).unwrap();
+        post("dave", "foo");
+        post("erin", "bar");
+
+        // posts from before networks were recorded are from any network
+        let users = |network| {
+            let p = db.check_prepost("example.com/a", "http://example.com/a", network, None, None)
+                .unwrap()
+                .unwrap();
+            (p.count, p.user, p.network, p.last_user, p.last_network)
+        };
+        assert_eq!((4, "alice".into(), None, "erin".into(), Some("bar".into())), users(None));
+        assert_eq!((3, "alice".into(), None, "dave".into(), Some("foo".into())), users(Some("foo")));
+        assert_eq!((2, "alice".into(), None, "carol".into(), None), users(Some("baz")));
+
+        let recent = |network| db.recent_posts(network, "#one", 10).unwrap().len();
+        assert_eq!((2, 1), (recent("foo"), recent("baz")));
+    }
+
+    #[test]
+    fn test_search_posts() {
+        let db = Database::open_in_memory().unwrap();
+        let post = |title, url, network| db.add_log(&NewLogEntry {
+            title,
+            url,
+            url_key: url,
+            user: "a",
+            channel: "#c",
+            network,
+        }).unwrap();
+        post("Rust 1.0 released", "http://example.com/rust", "net");
+        post("100% rust", "http://example.com/a_b", "net");
+        post("Rusty", "http://example.org/", "other");
+
+        let titles = |text| db.search_posts("net", "#c", text, 10).unwrap()
+            .into_iter()
+            .map(|p| p.title)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["100% rust", "Rust 1.0 released"], titles("RUST"));
+        assert_eq!(vec!["Rust 1.0 released"], titles("com/rust"));
+        assert_eq!(vec!["100% rust"], titles("0%"));
+        assert_eq!(vec!["100% rust"], titles("a_b"));
+        assert!(titles("a%b").is_empty());
+        assert_eq!(1, db.search_posts("net", "#c", "rust", 1).unwrap().len());
+        assert!(db.search_posts("net", "#d", "rust", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+
+        // several processes, or networks which don't share a store, may write
+        // to a database at once
+        let threads: Vec<_> = (0..4).map(|n| {
+            let path = path.clone();
+            thread::spawn(move || {
+                let db = Database::open(&path).unwrap();
+                let network = format!("net{}", n);
+                for _ in 0..25 {
+                    db.add_log(&NewLogEntry {
+                        title: "t",
+                        url: "http://example.com",
+                        url_key: "example.com",
+                        user: "a",
+                        channel: "#c",
+                        network: &network,
+                    }).unwrap();
+                }
+                db.prune(Utc::now() - chrono::Duration::days(1), true).unwrap();
+            })
+        }).collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let db = Database::open(&path).unwrap();
+        let p = db.check_prepost("example.com", "http://example.com", None, None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(100, p.count);
+
+        let mode: String = db.db.lock().unwrap()
+            .query_row("PRAGMA journal_mode", &[], |r| r.get(0))
+            .unwrap();
+        assert_eq!("wal", mode);
+    }
+
+    #[test]
+    fn test_check_and_add_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        Database::open(&path).unwrap();
+
+        // of posts made at once, by several processes, each sees all of the
+        // earlier ones, and none see the same number
+        let threads: Vec<_> = (0..4).map(|_| {
+            let path = path.clone();
+            thread::spawn(move || {
+                let db = Database::open(&path).unwrap();
+                (0..25).map(|_| {
+                    db.check_and_add_log(&NewLogEntry {
+                        title: "t",
+                        url: "http://example.com",
+                        url_key: "example.com",
+                        user: "a",
+                        channel: "#c",
+                        network: "net",
+                    }, None, None, None).unwrap().map_or(0, |p| p.count)
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+
+        let mut counts: Vec<_> = threads.into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        counts.sort_unstable();
+        assert_eq!((0..100).collect::<Vec<_>>(), counts);
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let time = parse_time("Sat Mar 7 09:05:01 2020").unwrap();
+        assert_eq!("2020-03-07T09:05:01+00:00", time.to_rfc3339());
+        assert_eq!(Some(time), parse_time(&time.format(TIME_FORMAT).to_string()));
+        assert_eq!(None, parse_time("yesterday"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let db = Database::open_in_memory().unwrap();
+        let since = Utc::now() - chrono::Duration::minutes(1);
+        let fail = |url, host, kind, host_failure| db.add_error(&NewErrorEntry {
+            url,
+            error_info: "error",
+            host,
+            kind,
+            status: None,
+            host_failure,
+            network: "net",
+            channel: "#c",
+        }).unwrap();
+
+        fail("http://a.com/1", "a.com", "no-title", false);
+        fail("http://a.com/1", "a.com", "no-title", false);
+        fail("http://a.com/2", "a.com", "timeout", true);
+        fail("http://b.com/", "b.com", "connect", true);
+
+        let counts = |url, host| db.failure_counts(url, host, since).unwrap();
+        assert_eq!(FailureCounts { url: 2, host: 1 }, counts("http://a.com/1", "a.com"));
+        assert_eq!(FailureCounts { url: 0, host: 1 }, counts("http://b.com/x", "b.com"));
+        assert_eq!(FailureCounts::default(), counts("http://c.com/", "c.com"));
+
+        let later = Utc::now() + chrono::Duration::minutes(1);
+        assert_eq!(FailureCounts::default(), db.failure_counts("http://a.com/1", "a.com", later).unwrap());
+
+        let hosts = db.failing_hosts(since, 10).unwrap();
+        assert_eq!(vec![
+            HostFailures { host: "a.com".into(), count: 3, last_kind: Some("timeout".into()) },
+            HostFailures { host: "b.com".into(), count: 1, last_kind: Some("connect".into()) },
+        ], hosts);
+        assert_eq!(1, db.failing_hosts(since, 1).unwrap().len());
+        assert!(db.failing_hosts(later, 10).unwrap().is_empty());
+
+        // old errors are pruned along with posts, or on their own
+        assert_eq!(0, db.prune_errors(since).unwrap());
+        assert_eq!(4, db.failing_hosts(since, 10).unwrap().iter().map(|h| h.count).sum::<u32>());
+        fail("http://b.com/", "b.com", "connect", true);
+        assert_eq!(5, db.prune_errors(later).unwrap());
+        assert!(db.failing_hosts(since, 10).unwrap().is_empty());
+        fail("http://b.com/", "b.com", "connect", true);
+        db.prune(later, false).unwrap();
+        assert!(db.failing_hosts(since, 10).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,292 @@
+/*
+ * Response templates
+ *
+ */
+use std::fmt;
+use chrono::{DateTime, Utc};
+use failure::{Error, bail};
+use serde::{Serializer, Deserializer, de};
+use serde_derive::{Serialize, Deserialize};
+
+/// placeholders available for all title responses
+const TITLE_VARS: [&str; 9] = [
+    "title", "url", "domain", "final_url", "mime", "size", "user", "channel",
+    "plugin",
+];
+
+/// placeholders available only for reposts
+const REPOST_VARS: [&str; 13] = [
+    "count", "prev_user", "prev_time", "prev_ago", "prev_channel", "prev_network",
+    "prev_location", "last_user", "last_time", "last_ago", "last_channel",
+    "last_network", "last_location",
+];
+
+/// placeholders available for error responses
+const ERROR_VARS: [&str; 5] = ["error", "url", "domain", "user", "channel"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Var(String),
+}
+
+/// A response template, where `{name}` is replaced with the value of a
+/// placeholder, and `{{` and `}}` produce literal braces
+#[derive(Debug, Clone)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = source.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) if c.is_ascii_lowercase() || c == '_' => name.push(c),
+                            Some(c) => bail!("invalid character `{}` in placeholder", c),
+                            None => bail!("unclosed `{`"),
+                        }
+                    }
+                    if name.is_empty() {
+                        bail!("empty placeholder `{}`");
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(text.split_off(0)));
+                    }
+                    parts.push(Part::Var(name));
+                },
+                '}' => bail!("unmatched `}`, use `}}` for a literal brace"),
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Self { source: source.to_string(), parts })
+    }
+
+    /// Check that all placeholders are in a list of those available
+    fn check_vars(&self, available: &[&str]) -> Result<(), Error> {
+        for part in &self.parts {
+            if let Part::Var(name) = part {
+                if !available.contains(&name.as_str()) {
+                    bail!("unknown placeholder `{{{}}}`, expected one of: {}",
+                        name, available.join(", "));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Render the template, with placeholders missing from `values` left
+    /// empty
+    pub fn render(&self, values: &[(&str, &str)]) -> String {
+        self.parts
+            .iter()
+            .map(|p| match p {
+                Part::Text(t) => t.as_str(),
+                Part::Var(v) => values
+                    .iter()
+                    .find(|(k, _)| k == v)
+                    .map_or("", |(_, v)| v),
+            })
+            .collect()
+    }
+}
+
+/// units of time, for describing how long ago something happened
+const TIME_UNITS: [(&str, i64); 6] = [
+    ("year", 365 * 86400),
+    ("month", 30 * 86400),
+    ("week", 7 * 86400),
+    ("day", 86400),
+    ("hour", 3600),
+    ("minute", 60),
+];
+
+/// Describe how long before `now` a time was, in the largest whole unit,
+/// e.g. `3 days ago`
+pub fn time_ago(time: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let secs = (now - time).num_seconds();
+
+    TIME_UNITS
+        .iter()
+        .find(|(_, unit)| secs >= *unit)
+        .map_or_else(|| "just now".to_string(), |(name, unit)| {
+            let n = secs / unit;
+            format!("{} {}{} ago", n, name, if n == 1 { "" } else { "s" })
+        })
+}
+
+impl serde::Serialize for Template {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Template {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = Template;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a response template")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Template::parse(v).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
+/// Templates for each kind of response
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Templates {
+    /// a title for a URL not posted before
+    pub title: Template,
+    /// a title for a URL which has been posted before
+    pub repost: Template,
+    /// a title provided by a plugin
+    pub plugin: Template,
+    /// an error retrieving a title
+    pub error: Template,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            title: Template::parse("⤷ {title}").unwrap(),
+            repost: Template::parse("⤷ {title} → {prev_time} {prev_user} ({prev_location})").unwrap(),
+            plugin: Template::parse("⤷ {title}").unwrap(),
+            error: Template::parse("{error}").unwrap(),
+        }
+    }
+}
+
+impl Templates {
+    pub fn validate(&self) -> Result<(), Error> {
+        let repost_vars: Vec<&str> = TITLE_VARS.iter().chain(&REPOST_VARS).copied().collect();
+
+        for (name, template, vars) in &[
+            ("title", &self.title, &TITLE_VARS[..]),
+            ("repost", &self.repost, &repost_vars[..]),
+            ("plugin", &self.plugin, &TITLE_VARS[..]),
+            ("error", &self.error, &ERROR_VARS[..]),
+        ] {
+            if let Err(e) = template.check_vars(vars) {
+                bail!("{}: {}", name, e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(t: &str, values: &[(&str, &str)]) -> String {
+        Template::parse(t).unwrap().render(values)
+    }
+
+    #[test]
+    fn test_render() {
+        let v = [("title", "A Page"), ("user", "nick")];
+
+        assert_eq!("", render("", &v));
+        assert_eq!("plain", render("plain", &v));
+        assert_eq!("⤷ A Page", render("⤷ {title}", &v));
+        assert_eq!("A Pagenick", render("{title}{user}", &v));
+        assert_eq!("[nick] A Page", render("[{user}] {title}", &v));
+        assert_eq!("missing: .", render("missing: {mime}.", &v));
+        assert_eq!("{title} {A Page}", render("{{title}} {{{title}}}", &v));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (t, e) in &[
+            ("{title", "unclosed `{`"),
+            ("title}", "unmatched `}`, use `}}` for a literal brace"),
+            ("{}", "empty placeholder `{}`"),
+            ("{Title}", "invalid character `T` in placeholder"),
+            ("{ti tle}", "invalid character ` ` in placeholder"),
+        ] {
+            assert_eq!(*e, Template::parse(t).unwrap_err().to_string());
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        Templates::default().validate().unwrap();
+
+        let mut t = Templates {
+            repost: Template::parse("{title} {prev_user} {final_url}").unwrap(),
+            ..Templates::default()
+        };
+        t.validate().unwrap();
+
+        t.title = Template::parse("{title} {prev_user}").unwrap();
+        let err = t.validate().unwrap_err().to_string();
+        assert!(err.starts_with("title: unknown placeholder `{prev_user}`"));
+
+        let t = Templates {
+            error: Template::parse("{title}").unwrap(),
+            ..Templates::default()
+        };
+        let err = t.validate().unwrap_err().to_string();
+        assert!(err.starts_with("error: unknown placeholder `{title}`"));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let t: Templates = toml::de::from_str("title = \"<{title}>\"").unwrap();
+        assert_eq!("<x>", t.title.render(&[("title", "x")]));
+        assert_eq!("⤷ x", t.plugin.render(&[("title", "x")]));
+
+        let err = toml::de::from_str::<Templates>("title = \"{title\"").unwrap_err();
+        assert!(err.to_string().contains("unclosed `{`"));
+    }
+
+    #[test]
+    fn test_time_ago() {
+        let now = Utc::now();
+        let ago = |secs| time_ago(now - chrono::Duration::seconds(secs), now);
+
+        assert_eq!("just now", ago(0));
+        assert_eq!("just now", ago(59));
+        assert_eq!("just now", ago(-10));
+        assert_eq!("1 minute ago", ago(60));
+        assert_eq!("2 hours ago", ago(2 * 3600 + 59));
+        assert_eq!("3 days ago", ago(3 * 86400));
+        assert_eq!("1 week ago", ago(13 * 86400));
+        assert_eq!("2 months ago", ago(65 * 86400));
+        assert_eq!("1 year ago", ago(400 * 86400));
+    }
+}
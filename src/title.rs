@@ -5,6 +5,7 @@ use image::{
     io::Reader,
 };
 use mime::Mime;
+use reqwest::Url;
 use scraper::{Html, Selector};
 
 use crate::{
@@ -67,10 +68,7 @@ fn parse_html_title(page_contents: &str) -> Option<String> {
 
 /// Attempt to extract a page title from downloaded HTML
 pub fn parse_title(page_contents: &str) -> Option<String> {
-    let title_dec = match parse_html_title(page_contents) {
-        Some(t) => t,
-        None => return None,
-    };
+    let title_dec = parse_html_title(page_contents)?;
 
     // make any multi-line title string into a single line,
     // trim leading and trailing whitespace
@@ -87,6 +85,98 @@ pub fn parse_title(page_contents: &str) -> Option<String> {
     Some(title_one_line)
 }
 
+/// Attempt to get the `<link rel="canonical">` of downloaded HTML
+pub fn parse_canonical(page_contents: &str) -> Option<String> {
+    let fragment = Html::parse_document(page_contents);
+    let link_selector = Selector::parse("link[rel~=canonical][href]").unwrap();
+
+    fragment
+        .select(&link_selector)
+        .next()
+        .and_then(|n| n.value().attr("href"))
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+}
+
+/// Split text into lower case alphanumeric words
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Levenshtein edit distance, in characters
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.iter().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let sub = diag + usize::from(ca != cb);
+            diag = row[j + 1];
+            row[j + 1] = sub.min(diag + 1).min(row[j] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Get the similarity of two strings of alphanumeric characters, from 0 to 1
+fn string_similarity(a: &str, b: &str) -> f64 {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let len = a.len().max(b.len());
+
+    if len == 0 {
+        return 0.0;
+    }
+
+    1.0 - edit_distance(&a, &b) as f64 / len as f64
+}
+
+/// titles longer than this are never compared by edit distance, as they
+/// are unlikely to be just a slug, and comparison is quadratic
+const EDIT_DISTANCE_MAX: usize = 200;
+
+/// Get how similar a title is to its URL, from 0 to 1, where 1 means the
+/// title says nothing the URL doesn't, e.g. it's the slug of the path, or the
+/// domain name
+///
+/// This is the greater of the proportion of words in the title which appear
+/// in the URL, and the edit distance similarity of the title and the host
+/// name, or any path segment, ignoring punctuation.
+pub fn url_similarity(url: &Url, title: &str) -> f64 {
+    let host = url.host_str().unwrap_or_default();
+    let host = host.strip_prefix("www.").unwrap_or(host);
+
+    let mut parts: Vec<&str> = vec![host, url.path()];
+    parts.extend(url.path_segments().into_iter().flatten());
+
+    let title_words = words(title);
+    let url_words: Vec<String> = parts.iter().flat_map(|p| words(p)).collect();
+
+    if title_words.is_empty() {
+        return 0.0;
+    }
+
+    let found = title_words.iter().filter(|w| url_words.contains(w)).count();
+    let overlap = found as f64 / title_words.len() as f64;
+
+    let title = title_words.concat();
+    if title.len() > EDIT_DISTANCE_MAX {
+        return overlap;
+    }
+
+    parts.iter()
+        .map(|p| {
+            // ignore any file extension
+            let p = p.rsplit_once('.').filter(|_| *p != host).map_or(*p, |(s, _)| s);
+            string_similarity(&title, &words(p).concat())
+        })
+        .fold(overlap, f64::max)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -167,6 +257,53 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_canonical_links() {
+        assert_eq!(None, parse_canonical("<title>t</title>"));
+        assert_eq!(None, parse_canonical("<link rel=\"canonical\" href=\" \">"));
+        assert_eq!(None, parse_canonical("<link rel=\"stylesheet\" href=\"/a.css\">"));
+        assert_eq!(
+            Some(String::from("https://example.com/a")),
+            parse_canonical("<head><link rel=\"canonical\" href=\"https://example.com/a\"></head>")
+        );
+        assert_eq!(
+            Some(String::from("/a")),
+            parse_canonical("<link rel=\"alternate canonical\" href=\"/a\">")
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        let d = |a: &str, b: &str| {
+            let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+            edit_distance(&a, &b)
+        };
+        assert_eq!(0, d("", ""));
+        assert_eq!(3, d("abc", ""));
+        assert_eq!(3, d("kitten", "sitting"));
+        assert_eq!(1, d("\u{2665}a", "a"));
+    }
+
+    #[test]
+    fn test_url_similarity() {
+        let sim = |u: &str, t: &str| url_similarity(&Url::parse(u).unwrap(), t);
+
+        // titles repeating the URL
+        assert_eq!(1.0, sim("https://blog.example.com/my-blog-post-title", "my-blog-post-title"));
+        assert_eq!(1.0, sim("https://example.com/2020/my_blog_post.html", "My Blog Post"));
+        assert_eq!(1.0, sim("https://www.example.com/", "example.com"));
+        assert_eq!(1.0, sim("https://www.example.com/", "Example"));
+        assert!(sim("https://example.com/blog/my-post-title", "my post titles") > 0.9);
+        assert!(sim("https://example.com/blog/mypost", "My Post") > 0.9);
+
+        // titles with more information
+        assert!(sim("https://example.com/my-post", "My post about things") <= 0.5);
+        assert!(sim("https://example.com/watch?v=abc", "A video") < 0.5);
+        assert!(sim("https://example.com/a", "Something else") < 0.2);
+        assert_eq!(0.0, sim("https://example.com/", ""));
+        assert_eq!(0.0, sim("http://127.0.0.1:28382/", "|t|"));
+    }
+
     #[test]
     fn get_metadata_from_local_images() {
         for test in vec!(
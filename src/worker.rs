@@ -0,0 +1,184 @@
+/*
+ * Worker threads, for work which mustn't block the IRC connection
+ *
+ */
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, Condvar},
+    thread,
+};
+use log::error;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct State {
+    jobs: VecDeque<Job>,
+    /// maximum number of jobs waiting for a worker
+    max_queued: usize,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// Closes the pool when the last handle is dropped
+struct Handle {
+    shared: Arc<Shared>,
+    threads: usize,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.cond.notify_all();
+    }
+}
+
+/// A fixed size pool of worker threads, taking jobs from a bounded queue
+///
+/// The threads exit once all clones of the pool have been dropped, and any
+/// jobs still waiting are discarded.
+#[derive(Clone)]
+pub struct Workers {
+    handle: Arc<Handle>,
+}
+
+impl Workers {
+    /// Start a pool of worker threads; with no threads, jobs are run by the
+    /// thread submitting them
+    pub fn new(threads: usize, max_queued: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                max_queued,
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        });
+
+        for _ in 0..threads {
+            let shared = shared.clone();
+            thread::spawn(move || run(&shared));
+        }
+
+        Self {
+            handle: Arc::new(Handle { shared, threads }),
+        }
+    }
+
+    /// Queue a job to be run by a worker, returning false, and dropping the
+    /// job, if too many jobs are already waiting
+    pub fn submit<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.handle.threads == 0 {
+            run_job(Box::new(job));
+            return true;
+        }
+
+        let shared = &self.handle.shared;
+        let mut state = shared.state.lock().unwrap();
+
+        if state.jobs.len() >= state.max_queued {
+            return false;
+        }
+
+        state.jobs.push_back(Box::new(job));
+        shared.cond.notify_one();
+        true
+    }
+}
+
+/// run a job, catching any panic so it doesn't take the worker with it
+fn run_job(job: Job) {
+    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        error!("worker job panicked");
+    }
+}
+
+/// run jobs, until the pool is closed
+fn run(shared: &Shared) {
+    let mut state = shared.state.lock().unwrap();
+
+    loop {
+        if state.closed {
+            return;
+        }
+
+        match state.jobs.pop_front() {
+            Some(job) => {
+                drop(state);
+                run_job(job);
+                state = shared.state.lock().unwrap();
+            },
+            None => state = shared.cond.wait(state).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Barrier, mpsc};
+
+    #[test]
+    fn test_workers() {
+        let workers = Workers::new(2, 10);
+        let (tx, rx) = mpsc::channel();
+
+        for n in 0..10 {
+            let tx = tx.clone();
+            assert!(workers.submit(move || tx.send(n).unwrap()));
+        }
+
+        let mut done: Vec<i32> = rx.iter().take(10).collect();
+        done.sort_unstable();
+        assert_eq!((0..10).collect::<Vec<_>>(), done);
+    }
+
+    #[test]
+    fn test_workers_bounded() {
+        let workers = Workers::new(1, 2);
+        let barrier = Arc::new(Barrier::new(2));
+        let (tx, rx) = mpsc::channel();
+
+        // block the only worker, until the queue has been filled
+        let b = barrier.clone();
+        assert!(workers.submit(move || { b.wait(); }));
+        while !workers.handle.shared.state.lock().unwrap().jobs.is_empty() {
+            thread::yield_now();
+        }
+
+        for n in 0..2 {
+            let tx = tx.clone();
+            assert!(workers.submit(move || tx.send(n).unwrap()));
+        }
+        assert!(!workers.submit(|| {}));
+
+        barrier.wait();
+        assert_eq!(vec![0, 1], rx.iter().take(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_workers_panic() {
+        let workers = Workers::new(1, 10);
+        let (tx, rx) = mpsc::channel();
+
+        assert!(workers.submit(|| panic!("job panicked")));
+        assert!(workers.submit(move || tx.send(()).unwrap()));
+        assert!(rx.recv().is_ok());
+    }
+
+    #[test]
+    fn test_workers_inline() {
+        let workers = Workers::new(0, 0);
+        let (tx, rx) = mpsc::channel();
+
+        assert!(workers.submit(move || tx.send(thread::current().id()).unwrap()));
+        assert_eq!(thread::current().id(), rx.recv().unwrap());
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * Fitting responses into IRC lines
+ *
+ */
+use unicode_segmentation::UnicodeSegmentation;
+
+/// maximum length of an IRC line, excluding the trailing CRLF, see RFC1459
+const LINE_MAX: usize = 510;
+
+/// length assumed for the `user@host` part of our hostmask, if it isn't
+/// known, from common `USERLEN` and `HOSTLEN` limits
+const USERHOST_MAX: usize = 10 + 1 + 63;
+
+/// Get the number of bytes available for the text of a message, once the
+/// server has added our hostmask, i.e. in `:nick!user@host PRIVMSG #chan :`
+pub fn payload_len(nick: &str, userhost: Option<&str>, command: &str, target: &str) -> usize {
+    let prefix = 1 + nick.len() + 1 + userhost.map_or(USERHOST_MAX, str::len) + 1;
+
+    LINE_MAX.saturating_sub(prefix + command.len() + 1 + target.len() + 2)
+}
+
+/// Get the largest grapheme boundary in `text` at or before `max` bytes
+fn floor_boundary(text: &str, max: usize) -> usize {
+    text.grapheme_indices(true)
+        .map(|(i, g)| i + g.len())
+        .take_while(|&end| end <= max)
+        .last()
+        .unwrap_or(0)
+}
+
+/// Truncate text to at most `max` bytes, without splitting grapheme
+/// clusters, ending with `ellipsis` if anything was removed
+pub fn truncate(text: &str, max: usize, ellipsis: &str) -> String {
+    if text.len() <= max {
+        return text.to_string();
+    }
+
+    if ellipsis.len() >= max {
+        return text[..floor_boundary(text, max)].to_string();
+    }
+
+    let kept = &text[..floor_boundary(text, max - ellipsis.len())];
+
+    if ellipsis.is_empty() {
+        kept.to_string()
+    } else {
+        format!("{}{}", kept.trim_end(), ellipsis)
+    }
+}
+
+/// Split text into lines of at most `max` bytes, at word boundaries where
+/// possible, and never splitting grapheme clusters
+///
+/// At most `1 + continuation` lines are produced, with the last truncated
+/// and ending with `ellipsis` if the text doesn't fit.
+pub fn split(text: &str, max: usize, continuation: usize, ellipsis: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut rest = text.trim();
+
+    while !rest.is_empty() {
+        if rest.len() <= max || lines.len() == continuation {
+            lines.push(truncate(rest, max, ellipsis));
+            break;
+        }
+
+        let end = floor_boundary(rest, max);
+        if end == 0 {
+            break;
+        }
+
+        // break before the last space which fits, otherwise mid-word
+        let at = if rest[end..].starts_with(' ') {
+            end
+        } else {
+            rest[..end].rfind(' ').filter(|&i| i > 0).unwrap_or(end)
+        };
+
+        lines.push(rest[..at].trim_end().to_string());
+        rest = rest[at..].trim_start();
+    }
+
+    lines.retain(|l| !l.is_empty());
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_len() {
+        // ":nick!user@host PRIVMSG #chan :"
+        assert_eq!(510 - 31, payload_len("nick", Some("user@host"), "PRIVMSG", "#chan"));
+        assert_eq!(510 - 30, payload_len("nick", Some("user@host"), "NOTICE", "#chan"));
+        assert_eq!(510 - 96, payload_len("nick", None, "PRIVMSG", "#chan"));
+        assert_eq!(0, payload_len(&"n".repeat(600), None, "PRIVMSG", "#chan"));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!("", truncate("", 10, ""));
+        assert_eq!("", truncate("", 1, ""));
+        assert_eq!(" ", truncate("  ", 1, ""));
+        assert_eq!("\u{2665}", truncate("\u{2665}", 4, ""));
+        assert_eq!("\u{2665}", truncate("\u{2665}", 3, ""));
+        assert_eq!("", truncate("\u{2665}", 2, ""));
+        assert_eq!("\u{0306}\u{0306}", truncate("\u{0306}\u{0306}", 4, ""));
+        assert_eq!("\u{0306}", truncate("\u{0306}", 2, ""));
+        assert_eq!("", truncate("\u{0306}", 1, ""));
+        assert_eq!("hello ", truncate("hello \u{1F603} world!", 9, ""));
+
+        // grapheme clusters aren't split
+        assert_eq!("", truncate("\u{0306}\u{0306}", 2, ""));
+        assert_eq!("e", truncate("ee\u{0301}", 2, ""));
+        assert_eq!("a", truncate("a\u{1F1EC}\u{1F1E7}", 7, ""));
+
+        assert_eq!("hello…", truncate("hello \u{1F603} world!", 10, "…"));
+        assert_eq!("hello world!", truncate("hello world!", 12, "…"));
+        assert_eq!("hello w...", truncate("hello world!", 10, "..."));
+        assert_eq!("hel", truncate("hello world!", 3, "..."));
+    }
+
+    #[test]
+    fn test_split() {
+        let text = "the quick brown fox jumps over the lazy dog";
+
+        assert_eq!(vec![text], split(text, 100, 0, "…"));
+        assert_eq!(vec!["the quick brown…"], split(text, 18, 0, "…"));
+        assert_eq!(
+            vec!["the quick brown", "fox jumps over the", "lazy dog"],
+            split(text, 18, 2, "…")
+        );
+        assert_eq!(
+            vec!["the quick brown", "fox jumps over…"],
+            split(text, 18, 1, "…")
+        );
+        assert_eq!(
+            vec!["the quick", "brown fox"],
+            split("the quick brown fox", 9, 3, "…")
+        );
+
+        // words longer than a line are split
+        assert_eq!(
+            vec!["abcde", "fghij", "k lmn"],
+            split("abcdefghijk lmn", 5, 5, "…")
+        );
+        assert_eq!(vec!["\u{2665}", "\u{2665}"], split("\u{2665}\u{2665}", 4, 5, ""));
+
+        assert!(split("", 10, 1, "…").is_empty());
+        assert!(split("   ", 10, 1, "…").is_empty());
+        assert!(split("\u{2665}", 2, 1, "").is_empty());
+    }
+}
@@ -0,0 +1,19 @@
+-- history database from before URLs were canonicalised, as created by every
+-- earlier release
+CREATE TABLE posts (
+    id              INTEGER PRIMARY KEY,
+    title           TEXT NOT NULL,
+    url             TEXT NOT NULL,
+    user            TEXT NOT NULL,
+    channel         TEXT NOT NULL,
+    time_created    TEXT NOT NULL
+);
+CREATE TABLE errors (
+    id              INTEGER PRIMARY KEY,
+    url             TEXT NOT NULL,
+    error_info      TEXT NOT NULL
+);
+INSERT INTO posts VALUES (1, 'a', 'http://example.com/a', 'alice', '#one', 'Sat Mar 7 09:05:01 2020');
+INSERT INTO posts VALUES (2, 'b', 'http://example.com/b', 'bob', '#two', 'Sun Mar 8 23:59:59 2020');
+INSERT INTO posts VALUES (3, 'a', 'http://example.com/a', 'carol', '#two', 'Mon Jan 4 00:00:00 2021');
+INSERT INTO errors VALUES (1, 'http://example.com/c', 'failed to parse title');